@@ -0,0 +1,20 @@
+//! Replays fuzzer inputs as sequences of `malloc`, `free` and `realloc`,
+//! checking every allocation's contents and the heap invariants as it goes.
+//!
+//! Needs nightly and `cargo install cargo-fuzz`. From the repository root:
+//!
+//! ```sh
+//! cargo +nightly fuzz run malloc_free
+//! ```
+//!
+//! Crashing inputs land in `fuzz/artifacts/malloc_free/` and replay with
+//! `cargo +nightly fuzz run malloc_free <file>`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/ops/mod.rs"]
+mod ops;
+
+fuzz_target!(|data: &[u8]| ops::run(data));
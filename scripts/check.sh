@@ -0,0 +1,16 @@
+#!/bin/sh
+# Builds, lints and tests the crate in the configurations it supports.
+set -eu
+cd "$(dirname "$0")/.."
+
+cargo build --no-default-features
+cargo clippy --all-targets --no-default-features -- -D warnings
+cargo test --lib --no-default-features
+
+cargo clippy --all-targets -- -D warnings
+cargo test
+
+features=ffi,redzone,poison,leak-check,size-map
+cargo clippy --all-targets --features "$features" -- -D warnings
+cargo test --features "$features"
+cargo rustc --lib --features ffi --crate-type cdylib
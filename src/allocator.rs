@@ -0,0 +1,689 @@
+//! Allocators with heaps of their own.
+//!
+//! The global entry points share the per-thread arenas. An [`Allocator`]
+//! keeps its own heap list instead, sized by an [`AllocatorConfig`], which
+//! makes it usable as an independent pool.
+
+use core::ffi::c_void;
+#[cfg(feature = "allocator_api")]
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::{self, NonNull},
+};
+#[cfg(feature = "std")]
+use std::{ops::Deref, sync::Arc};
+
+#[cfg(feature = "std")]
+use crate::heap_snapshot;
+use crate::{
+    call_site, class_histogram, heap_alloc, heap_free, heap_reserve, heap_reset, heap_stats,
+    heap_usable_size,
+    sync::{DefaultLock, HeapLock},
+    FreeError, HeapHandle, HeapStats, BIN_COUNT, BLOCKS_PER_HEAP, MIN_ALIGN, MIN_REMAINDER,
+    PAGE_SIZE, QUARANTINE_SLOTS, SMALL_HEAP_ALLOCATION_SIZE, TINY_HEAP_ALLOCATION_SIZE,
+};
+#[cfg(feature = "allocator_api")]
+use crate::{heap_allocate, heap_grow_in_place, heap_shrink_in_place};
+
+/// Sizes used by an [`Allocator`].
+///
+/// ```
+/// use my_alloc::AllocatorConfig;
+///
+/// let pool = AllocatorConfig::new()
+///     .tiny_heap_size(4096)
+///     .small_heap_size(16 * 1024)
+///     .large_threshold(8 * 1024)
+///     .build();
+/// let ptr = pool.alloc(100);
+/// unsafe { pool.free(ptr) }.unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorConfig {
+    pub(crate) tiny_heap_size: usize,
+    pub(crate) small_heap_size: usize,
+    pub(crate) large_threshold: usize,
+    pub(crate) min_split: usize,
+    pub(crate) trim_threshold: usize,
+    pub(crate) quarantine: usize,
+    pub(crate) rounding_cap: usize,
+}
+
+impl AllocatorConfig {
+    /// The sizes the global entry points use: 16 KiB tiny heaps, 128 KiB
+    /// small heaps, and a mapping of its own for anything above 128 KiB.
+    /// Blocks are split when that frees at least 32 bytes, and only a lone
+    /// empty heap stays mapped.
+    pub const fn new() -> Self {
+        Self {
+            tiny_heap_size: TINY_HEAP_ALLOCATION_SIZE,
+            small_heap_size: SMALL_HEAP_ALLOCATION_SIZE,
+            large_threshold: SMALL_HEAP_ALLOCATION_SIZE,
+            min_split: MIN_REMAINDER,
+            trim_threshold: 0,
+            quarantine: 0,
+            rounding_cap: 0,
+        }
+    }
+
+    /// Size of each mapping shared by tiny blocks, rounded up to whole pages.
+    /// Tiny blocks are those of up to 1/128th of it.
+    pub const fn tiny_heap_size(mut self, bytes: usize) -> Self {
+        self.tiny_heap_size = heap_size(bytes);
+        self
+    }
+
+    /// Size of each mapping shared by small blocks, rounded up to whole
+    /// pages. Small blocks are those bigger than tiny ones and of up to
+    /// 1/128th of it.
+    pub const fn small_heap_size(mut self, bytes: usize) -> Self {
+        self.small_heap_size = heap_size(bytes);
+        self
+    }
+
+    /// Requests above `bytes` get a mapping of their own instead of a block
+    /// in a heap.
+    pub const fn large_threshold(mut self, bytes: usize) -> Self {
+        self.large_threshold = bytes;
+        self
+    }
+
+    /// Splits a block handed out for a smaller request only when the rest
+    /// would hold at least `bytes`, rounded up to a multiple of 16. Bigger
+    /// values leave fewer, larger free blocks behind at the cost of slack
+    /// inside the blocks handed out.
+    pub const fn min_split(mut self, bytes: usize) -> Self {
+        let bytes = if bytes == 0 { 1 } else { bytes };
+        self.min_split = bytes.saturating_add(MIN_ALIGN - 1) & !(MIN_ALIGN - 1);
+        self
+    }
+
+    /// Keeps heaps with no live block mapped for reuse, as long as they add
+    /// up to no more than `bytes`. A lone empty heap always stays mapped.
+    /// [`trim`](crate::trim) releases them either way.
+    pub const fn trim_threshold(mut self, bytes: usize) -> Self {
+        self.trim_threshold = bytes;
+        self
+    }
+
+    /// Holds the last `blocks` freed back from reuse, up to 64, like
+    /// [`set_quarantine`](crate::set_quarantine). Zero holds none.
+    pub const fn quarantine(mut self, blocks: usize) -> Self {
+        self.quarantine = if blocks < QUARANTINE_SLOTS {
+            blocks
+        } else {
+            QUARANTINE_SLOTS
+        };
+        self
+    }
+
+    /// Rounds requests of up to `bytes` up to the next power of two, so that
+    /// requests of similar sizes fit each other's freed blocks, at the cost
+    /// of up to half of each block going unused. Zero, the default, only
+    /// rounds to multiples of 16.
+    pub const fn round_to_powers_of_two(mut self, bytes: usize) -> Self {
+        self.rounding_cap = bytes;
+        self
+    }
+
+    /// Creates an allocator with these sizes and no memory mapped yet.
+    pub fn build(self) -> Allocator {
+        self.build_with_lock()
+    }
+
+    /// Like [`build`](Self::build), guarding the heaps with `L` instead of
+    /// the default lock.
+    ///
+    /// ```
+    /// use my_alloc::{AllocatorConfig, NoLock};
+    ///
+    /// let pool = AllocatorConfig::new().build_with_lock::<NoLock>();
+    /// let ptr = pool.alloc(100);
+    /// unsafe { pool.free(ptr) }.unwrap();
+    /// ```
+    pub fn build_with_lock<L: HeapLock>(self) -> Allocator<L> {
+        Allocator {
+            handle: L::new(HeapHandle::with_config(self)),
+        }
+    }
+
+    pub(crate) fn tiny_block_size(&self) -> usize {
+        self.tiny_heap_size / BLOCKS_PER_HEAP
+    }
+
+    pub(crate) fn small_block_size(&self) -> usize {
+        self.small_heap_size / BLOCKS_PER_HEAP
+    }
+}
+
+impl Default for AllocatorConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rounds a heap size up to whole pages, one at least.
+const fn heap_size(bytes: usize) -> usize {
+    let pages = bytes.div_ceil(PAGE_SIZE);
+    if pages == 0 {
+        PAGE_SIZE
+    } else {
+        pages * PAGE_SIZE
+    }
+}
+
+/// An allocator with a heap list of its own, separate from the one behind
+/// [`malloc`](crate::malloc) and [`free`](crate::free).
+///
+/// Pointers must go back to the allocator that handed them out. Dropping the
+/// allocator unmaps everything it mapped, so every pointer it handed out
+/// becomes dangling.
+///
+/// `L` picks how the heaps are guarded; see [`HeapLock`].
+pub struct Allocator<L: HeapLock = DefaultLock> {
+    handle: L::Lock<HeapHandle>,
+}
+
+impl Allocator {
+    /// Creates an allocator with the default sizes. Nothing is mapped until
+    /// the first allocation.
+    pub fn new() -> Self {
+        AllocatorConfig::new().build()
+    }
+}
+
+impl<L: HeapLock> Allocator<L> {
+    /// Allocates `size` bytes, like [`malloc`](crate::malloc).
+    #[cfg_attr(feature = "leak-check", track_caller)]
+    pub fn alloc(&self, size: usize) -> *mut c_void {
+        let site = call_site();
+        let mut handle = L::lock(&self.handle);
+        handle.site = site;
+        heap_alloc(&mut handle, size)
+    }
+
+    /// Releases an allocation handed out by [`alloc`](Self::alloc).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`free`](crate::free), with `ptr` coming from this allocator.
+    pub unsafe fn free(&self, ptr: *mut c_void) -> Result<(), FreeError> {
+        heap_free(&mut L::lock(&self.handle), ptr)
+    }
+
+    /// Whether `ptr` is a live allocation handed out by this allocator.
+    pub fn contains(&self, ptr: *const c_void) -> bool {
+        heap_usable_size(&L::lock(&self.handle), ptr).is_some()
+    }
+
+    /// Maps heaps for `count` allocations of `size` bytes ahead of time, like
+    /// [`reserve`](crate::reserve).
+    pub fn reserve(&self, size: usize, count: usize) -> bool {
+        heap_reserve(&mut L::lock(&self.handle), size, count)
+    }
+
+    /// Reports how much memory this allocator has mapped and how much of it
+    /// is in use.
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+        heap_stats(&L::lock(&self.handle), &mut stats);
+        stats
+    }
+
+    /// The most bytes this allocator's live allocations have held at once,
+    /// like [`high_watermark`](crate::high_watermark).
+    pub fn high_watermark(&self) -> usize {
+        L::lock(&self.handle).peak_bytes
+    }
+
+    /// Starts [`high_watermark`](Self::high_watermark) over from the bytes
+    /// live right now.
+    pub fn reset_high_watermark(&self) {
+        let mut handle = L::lock(&self.handle);
+        handle.peak_bytes = handle.live_bytes;
+    }
+
+    /// This allocator's live allocations by size class, like
+    /// [`size_class_histogram`](crate::size_class_histogram).
+    pub fn size_class_histogram(&self) -> [(usize, usize); BIN_COUNT] {
+        class_histogram(&L::lock(&self.handle).class_counts)
+    }
+
+    /// Copies every live allocation into a new allocator with the same
+    /// sizes, packed together in the order they are laid out here. Returns
+    /// it along with the old and new address of each allocation, or `None`
+    /// when it runs out of memory. Tags come along. An allocation aligned
+    /// beyond 16 bytes is copied whole from the start of its block, so its
+    /// alignment is lost.
+    ///
+    /// The bytes are copied as they are, so pointers stored in them still
+    /// point into this allocator. Only data free of such pointers, such as
+    /// plain values or offsets, makes a usable snapshot.
+    #[cfg(feature = "std")]
+    pub fn clone_into_new_arena(&self) -> Option<(Self, Vec<(*mut c_void, *mut c_void)>)> {
+        let handle = L::lock(&self.handle);
+        let mut copy = HeapHandle::with_config(handle.config);
+        let moved = heap_snapshot(&handle, &mut copy);
+        let clone = Allocator {
+            handle: L::new(copy),
+        };
+        Some((clone, moved?))
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lets collections allocate from the pool, as in `Vec::new_in(&pool)`.
+/// Growing and shrinking stay in place whenever the block allows it.
+#[cfg(feature = "allocator_api")]
+unsafe impl<L: HeapLock> core::alloc::Allocator for Allocator<L> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut handle = L::lock(&self.handle);
+        let ptr = heap_allocate(&mut handle, layout);
+        granted(&handle, ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        // There is no way to report a failure here.
+        let _ = heap_free(&mut L::lock(&self.handle), ptr.as_ptr() as *mut c_void);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // Over-aligned allocations do not start at their block's data.
+        if old_layout.align().max(new_layout.align()) <= MIN_ALIGN {
+            let mut handle = L::lock(&self.handle);
+            let data = ptr.as_ptr() as *mut c_void;
+            if heap_grow_in_place(&mut handle, data, new_layout.size()) {
+                return granted(&handle, data);
+            }
+        }
+        self.relocate(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.align().max(new_layout.align()) <= MIN_ALIGN {
+            let mut handle = L::lock(&self.handle);
+            let data = ptr.as_ptr() as *mut c_void;
+            if heap_shrink_in_place(&mut handle, data, new_layout.size()) {
+                return granted(&handle, data);
+            }
+        }
+        self.relocate(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<L: HeapLock> Allocator<L> {
+    /// Moves an allocation to a new block for `new_layout`.
+    unsafe fn relocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = core::alloc::Allocator::allocate(self, new_layout)?;
+        let size = old_layout.size().min(new_layout.size());
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast::<u8>().as_ptr(), size);
+        core::alloc::Allocator::deallocate(self, ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+/// The block at `ptr` with all of its usable bytes, or an error for null.
+#[cfg(feature = "allocator_api")]
+fn granted(handle: &HeapHandle, ptr: *mut c_void) -> Result<NonNull<[u8]>, AllocError> {
+    let data = NonNull::new(ptr as *mut u8).ok_or(AllocError)?;
+    let size = heap_usable_size(handle, ptr).ok_or(AllocError)?;
+    Ok(NonNull::slice_from_raw_parts(data, size))
+}
+
+/// A handle to an [`Allocator`] that many threads can hold at once.
+///
+/// Clones share the same heaps, and any of them may free what another one
+/// allocated. The heaps are unmapped once the last clone is dropped. Needs
+/// `std`.
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub struct SharedAllocator {
+    allocator: Arc<Allocator>,
+}
+
+#[cfg(feature = "std")]
+impl SharedAllocator {
+    /// Creates a shared allocator with the default sizes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Allocator> for SharedAllocator {
+    fn from(allocator: Allocator) -> Self {
+        Self {
+            allocator: Arc::new(allocator),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Deref for SharedAllocator {
+    type Target = Allocator;
+
+    fn deref(&self) -> &Allocator {
+        &self.allocator
+    }
+}
+
+impl<L: HeapLock> Drop for Allocator<L> {
+    fn drop(&mut self) {
+        // Leaves the handle empty, so a second teardown has nothing to unmap.
+        heap_reset(L::get_mut(&mut self.handle));
+    }
+}
+
+#[cfg(test)]
+mod allocator_tests {
+    use super::{Allocator, AllocatorConfig};
+    use crate::{mmap::LIVE_MAPS, FreeError, HeapLock, NoLock, SpinLock, PAGE_SIZE};
+
+    #[test]
+    fn high_watermark_remembers_the_peak() {
+        let pool = Allocator::new();
+        let ptrs: Vec<_> = (0..16).map(|_| pool.alloc(1000)).collect();
+        let peak = pool.high_watermark();
+        assert!(peak >= 16 * 1000);
+        for ptr in ptrs.into_iter().skip(4) {
+            unsafe { pool.free(ptr) }.unwrap();
+        }
+        // The peak stays where it was while usage drops.
+        assert_eq!(pool.high_watermark(), peak);
+        pool.reset_high_watermark();
+        let current = pool.high_watermark();
+        assert!(current >= 4 * 1000 && current < peak);
+    }
+
+    #[test]
+    fn histogram_counts_allocations_by_size_class() {
+        let pool = Allocator::new();
+        let mix = [(16, 3), (100, 5), (1000, 2), (1 << 20, 1)];
+        let ptrs: Vec<_> = mix
+            .iter()
+            .flat_map(|&(size, count)| (0..count).map(move |_| size))
+            .map(|size| pool.alloc(size))
+            .collect();
+        let histogram = pool.size_class_histogram();
+        assert_eq!(histogram[0], (16, 3));
+        // 100 bytes round up to 112, in the class from 64 up.
+        assert_eq!(histogram[2], (64, 5));
+        assert_eq!(histogram[5], (512, 2));
+        assert_eq!(histogram[15], (16 << 15, 1));
+        let counted: usize = histogram.iter().map(|&(_, count)| count).sum();
+        assert_eq!(counted, ptrs.len());
+
+        for ptr in ptrs {
+            unsafe { pool.free(ptr) }.unwrap();
+        }
+        assert!(pool
+            .size_class_histogram()
+            .iter()
+            .all(|&(_, count)| count == 0));
+    }
+
+    // With `ffi` the threads' own allocations map memory too.
+    #[cfg(all(feature = "std", not(feature = "ffi")))]
+    #[test]
+    fn shared_allocator_is_torn_down_by_the_last_clone() {
+        use std::{sync::mpsc, thread};
+
+        use super::SharedAllocator;
+
+        let pool = SharedAllocator::new();
+        let (sender, receiver) = mpsc::channel();
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let pool = pool.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    let before = LIVE_MAPS.get();
+                    let ptrs: Vec<_> = (0..200).map(|i| pool.alloc(8 + i * 40) as usize).collect();
+                    for ptr in ptrs.iter().skip(worker % 2) {
+                        unsafe { pool.free(*ptr as *mut _) }.unwrap();
+                    }
+                    // Leave one allocation for another thread to free.
+                    if worker % 2 == 1 {
+                        sender.send(ptrs[0]).unwrap();
+                    }
+                    LIVE_MAPS.get() - before
+                })
+            })
+            .collect();
+        drop(sender);
+        let mut mapped: isize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+        for ptr in receiver {
+            unsafe { pool.free(ptr as *mut _) }.unwrap();
+        }
+        assert_eq!(pool.stats().total().live_blocks, 0);
+
+        let before = LIVE_MAPS.get();
+        drop(pool);
+        mapped += LIVE_MAPS.get() - before;
+        assert_eq!(mapped, 0);
+    }
+
+    #[test]
+    fn drop_unmaps_everything() {
+        let before = LIVE_MAPS.get();
+        {
+            let pool = Allocator::new();
+            let _tiny = pool.alloc(16);
+            let _small = pool.alloc(1000);
+            let _large = pool.alloc(200_000);
+            let cached = pool.alloc(300_000);
+            unsafe { pool.free(cached) }.unwrap();
+            assert_eq!(LIVE_MAPS.get(), before + 4);
+        }
+        assert_eq!(LIVE_MAPS.get(), before);
+    }
+
+    #[test]
+    fn allocators_keep_their_heaps_apart() {
+        let first = Allocator::new();
+        let second = Allocator::new();
+        let a = first.alloc(64);
+        let b = second.alloc(64);
+        assert_ne!(a, b);
+        assert!(first.contains(a) && !first.contains(b));
+        assert!(second.contains(b) && !second.contains(a));
+        assert_eq!(first.stats().tiny.live_blocks, 1);
+        assert_eq!(second.stats().tiny.live_blocks, 1);
+
+        // Neither accepts the other's pointers, nor the global heap's.
+        assert_eq!(unsafe { second.free(a) }, Err(FreeError::InvalidPointer));
+        let global = crate::malloc(64);
+        assert_eq!(
+            unsafe { first.free(global) },
+            Err(FreeError::InvalidPointer)
+        );
+        unsafe { crate::free(global) };
+
+        unsafe { first.free(a) }.unwrap();
+        assert!(!first.contains(a));
+        assert_eq!(first.stats().tiny.live_blocks, 0);
+        assert_eq!(second.stats().tiny.live_blocks, 1);
+        unsafe { second.free(b) }.unwrap();
+        assert_eq!(second.stats().tiny.live_blocks, 0);
+    }
+
+    #[test]
+    fn custom_sizes_pick_the_heap_group() {
+        let pool = AllocatorConfig::new()
+            .tiny_heap_size(PAGE_SIZE)
+            .small_heap_size(2 * PAGE_SIZE)
+            .large_threshold(1024)
+            .build();
+        // Tiny blocks go up to 32 bytes, small ones up to 64.
+        let tiny = pool.alloc(8);
+        let small = pool.alloc(40);
+        let in_heap = pool.alloc(512);
+        let standalone = pool.alloc(2048);
+
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.tiny.mappings, stats.tiny.mapped_bytes),
+            (1, PAGE_SIZE)
+        );
+        assert_eq!(
+            (stats.small.mappings, stats.small.mapped_bytes),
+            (1, 2 * PAGE_SIZE)
+        );
+        // One exact-size heap and one mapping of its own.
+        assert_eq!((stats.large.mappings, stats.large.live_blocks), (2, 2));
+        for ptr in [tiny, small, in_heap, standalone] {
+            unsafe { pool.free(ptr) }.unwrap();
+        }
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn collections_allocate_from_the_pool() {
+        let pool = Allocator::new();
+        let mut numbers = Vec::new_in(&pool);
+        for i in 0..10_000u64 {
+            numbers.push(i);
+        }
+        assert!(pool.contains(numbers.as_ptr() as *const _));
+        while numbers.len() > 10 {
+            assert_eq!(numbers.pop(), Some(numbers.len() as u64));
+        }
+        numbers.shrink_to_fit();
+        assert_eq!(numbers, (0..10).collect::<Vec<_>>());
+        drop(numbers);
+        assert_eq!(pool.stats().total().live_blocks, 0);
+
+        // Over-aligned elements get aligned blocks.
+        #[repr(align(64))]
+        struct Line(#[allow(dead_code)] u8);
+        let mut lines = Vec::new_in(&pool);
+        for i in 0..100 {
+            lines.push(Line(i));
+            assert_eq!(lines.as_ptr() as usize % 64, 0);
+        }
+    }
+
+    #[test]
+    fn heap_sizes_round_up_to_pages() {
+        let config = AllocatorConfig::new()
+            .tiny_heap_size(1)
+            .small_heap_size(PAGE_SIZE + 1);
+        assert_eq!(config.tiny_heap_size, PAGE_SIZE);
+        assert_eq!(config.small_heap_size, 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn min_split_rounds_up_to_alignment() {
+        let sizes = [0, 1, 16, 17, usize::MAX].map(|bytes| AllocatorConfig::new().min_split(bytes));
+        assert_eq!(
+            sizes.map(|config| config.min_split),
+            [16, 16, 16, 32, usize::MAX & !15]
+        );
+    }
+
+    /// Allocates and frees through a pool guarded by `L`.
+    fn churn<L: HeapLock>() {
+        let pool = AllocatorConfig::new().build_with_lock::<L>();
+        let ptrs: Vec<_> = (0..100).map(|i| pool.alloc(16 + i * 24)).collect();
+        assert!(ptrs.iter().all(|ptr| pool.contains(*ptr)));
+        assert_eq!(pool.stats().total().live_blocks, 100);
+        for ptr in ptrs {
+            unsafe { pool.free(ptr) }.unwrap();
+        }
+        assert_eq!(pool.stats().total().live_blocks, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn snapshots_copy_the_live_blocks() {
+        let pool = Allocator::new();
+        let sizes = [24, 500, 200_000];
+        let ptrs = sizes.map(|size| pool.alloc(size));
+        let freed = pool.alloc(64);
+        for (i, (ptr, size)) in ptrs.iter().zip(sizes).enumerate() {
+            unsafe { ptr.cast::<u8>().write_bytes(i as u8 + 1, size) };
+        }
+        unsafe { pool.free(freed) }.unwrap();
+
+        let (clone, moved) = pool.clone_into_new_arena().unwrap();
+        assert_eq!(moved.len(), 3);
+        for (ptr, size) in ptrs.iter().zip(sizes) {
+            let (_, copy) = *moved.iter().find(|(old, _)| old == ptr).unwrap();
+            assert!(clone.contains(copy));
+            assert!(!pool.contains(copy));
+            let [old, new] = [*ptr, copy]
+                .map(|ptr| unsafe { std::slice::from_raw_parts(ptr.cast::<u8>(), size) });
+            assert_eq!(old, new);
+        }
+        assert_eq!(clone.stats().total().live_blocks, 3);
+    }
+
+    #[test]
+    fn every_lock_strategy_allocates() {
+        #[cfg(feature = "std")]
+        churn::<crate::MutexLock>();
+        churn::<SpinLock>();
+        churn::<NoLock>();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn spinlocked_pools_are_shared_between_threads() {
+        use std::thread;
+
+        let pool = AllocatorConfig::new().build_with_lock::<SpinLock>();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for size in 0..500 {
+                        let ptr = pool.alloc(size);
+                        unsafe { pool.free(ptr) }.unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.stats().total().live_blocks, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn lock_strategy_benchmark() {
+        use std::time::{Duration, Instant};
+
+        fn time<L: HeapLock>() -> Duration {
+            let pool = AllocatorConfig::new().build_with_lock::<L>();
+            let start = Instant::now();
+            for _ in 0..1_000_000 {
+                let ptr = pool.alloc(32);
+                unsafe { pool.free(ptr) }.unwrap();
+            }
+            start.elapsed()
+        }
+
+        println!("mutex: {:?}", time::<crate::MutexLock>());
+        println!("spinlock: {:?}", time::<SpinLock>());
+        println!("no lock: {:?}", time::<NoLock>());
+    }
+}
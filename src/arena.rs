@@ -0,0 +1,285 @@
+//! Per-thread arenas.
+//!
+//! Every thread allocates from an arena of its own so that threads do not
+//! serialize on a single lock. Arenas live in mapped memory, are never
+//! unmapped and are handed to the next new thread once their owner exits.
+//! `heap_anchor` stays around as the shared arena, used whenever a thread
+//! cannot reach its own (while it is being set up or torn down). Without
+//! `std` there are no thread locals, and every thread uses `heap_anchor`.
+
+#[cfg(feature = "std")]
+use core::{cell::Cell, mem};
+use core::{
+    ffi::c_void,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+#[cfg(feature = "std")]
+use crate::mmap::{mem_map, MMAP_NO_FLAGS, MMAP_PROT_READ_WRITE};
+use crate::{
+    default_config, heap_anchor, lock, owns,
+    sync::{DefaultLock, HeapLock, Mutex},
+    ArenaLock, HeapHandle, EMERGENCY_CONFIG,
+};
+
+struct Arena {
+    handle: ArenaLock,
+    /// Next arena in the registry. Written once, before the arena is
+    /// published.
+    next: *mut Arena,
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    in_use: AtomicBool,
+}
+
+/// Head of the registry of every arena ever created.
+static ARENAS: AtomicPtr<Arena> = AtomicPtr::new(ptr::null_mut());
+
+/// Marks a thread that must use the shared arena, because it is still
+/// claiming its own or has already released it.
+const SHARED: *mut Arena = ptr::dangling_mut();
+
+#[cfg(feature = "std")]
+thread_local! {
+    static THREAD_ARENA: Cell<*mut Arena> = const { Cell::new(ptr::null_mut()) };
+    static RELEASE: ArenaRelease = const { ArenaRelease };
+}
+
+/// Hands the thread's arena back to the registry when the thread exits.
+#[cfg(feature = "std")]
+struct ArenaRelease;
+
+#[cfg(feature = "std")]
+impl Drop for ArenaRelease {
+    fn drop(&mut self) {
+        let _ = THREAD_ARENA.try_with(|slot| {
+            let arena = slot.replace(SHARED);
+            if arena != SHARED && !arena.is_null() {
+                unsafe { (*arena).in_use.store(false, Ordering::Release) };
+            }
+        });
+    }
+}
+
+#[cfg(feature = "std")]
+fn claim_arena() -> *mut Arena {
+    let mut arena = ARENAS.load(Ordering::Acquire);
+    while !arena.is_null() {
+        let claimed = unsafe { &(*arena).in_use }
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if claimed {
+            return arena;
+        }
+        arena = unsafe { (*arena).next };
+    }
+
+    let Ok(memory) = mem_map(mem::size_of::<Arena>(), MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS) else {
+        return SHARED;
+    };
+    let arena = memory as *mut Arena;
+    let mut head = ARENAS.load(Ordering::Acquire);
+    loop {
+        unsafe {
+            arena.write(Arena {
+                handle: DefaultLock::new(HeapHandle::new()),
+                next: head,
+                in_use: AtomicBool::new(true),
+            });
+        }
+        match ARENAS.compare_exchange(head, arena, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return arena,
+            Err(current) => head = current,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn current_arena() -> *mut Arena {
+    THREAD_ARENA
+        .try_with(|slot| {
+            if slot.get().is_null() {
+                slot.set(SHARED);
+                let arena = claim_arena();
+                // Registering the release hook may itself allocate; those
+                // allocations see `SHARED` and go to the shared arena.
+                let _ = RELEASE.try_with(|_| ());
+                slot.set(arena);
+            }
+            slot.get()
+        })
+        .unwrap_or(SHARED)
+}
+
+#[cfg(not(feature = "std"))]
+fn current_arena() -> *mut Arena {
+    SHARED
+}
+
+/// Locks an arena, bringing its sizes up to date with the global settings.
+fn lock_arena(mutex: &ArenaLock) -> <DefaultLock as HeapLock>::Guard<'_, HeapHandle> {
+    let mut handle = lock(mutex);
+    handle.config = default_config();
+    handle
+}
+
+/// Runs `f` on the calling thread's arena.
+pub(crate) fn with_arena<R>(f: impl FnOnce(&mut HeapHandle) -> R) -> R {
+    let arena = current_arena();
+    let mutex: &ArenaLock = if arena == SHARED {
+        heap_anchor()
+    } else {
+        unsafe { &(*arena).handle }
+    };
+    f(&mut lock_arena(mutex))
+}
+
+/// Spare heaps for [`try_malloc_emergency`](crate::try_malloc_emergency),
+/// kept apart from the arenas so that ordinary allocations cannot use them
+/// up. Its sizes are its own rather than the global settings.
+static EMERGENCY: ArenaLock = Mutex::new(HeapHandle::with_config(EMERGENCY_CONFIG));
+
+/// Runs `f` on the emergency pool.
+pub(crate) fn with_emergency<R>(f: impl FnOnce(&mut HeapHandle) -> R) -> R {
+    f(&mut lock(&EMERGENCY))
+}
+
+/// Runs `f` on whichever arena handed out `ptr`, trying the calling thread's
+/// own arena first and the emergency pool last. Returns `None` if none of
+/// them owns it.
+pub(crate) fn with_owner<R>(
+    ptr: *const c_void,
+    f: impl FnOnce(&mut HeapHandle) -> R,
+) -> Option<R> {
+    with_arena_where(|handle| owns(handle, ptr), f)
+}
+
+/// Runs `f` on the first arena `matches` picks, in the order of
+/// [`with_owner`]. Returns `None` if it picks none.
+pub(crate) fn with_arena_where<R>(
+    matches: impl Fn(&HeapHandle) -> bool,
+    f: impl FnOnce(&mut HeapHandle) -> R,
+) -> Option<R> {
+    let own = current_arena();
+    if own != SHARED {
+        let mut handle = lock_arena(unsafe { &(*own).handle });
+        if matches(&handle) {
+            return Some(f(&mut handle));
+        }
+    }
+    {
+        let mut handle = lock_arena(heap_anchor());
+        if matches(&handle) {
+            return Some(f(&mut handle));
+        }
+    }
+    let mut arena = ARENAS.load(Ordering::Acquire);
+    while !arena.is_null() {
+        if arena != own {
+            let mut handle = lock_arena(unsafe { &(*arena).handle });
+            if matches(&handle) {
+                return Some(f(&mut handle));
+            }
+        }
+        arena = unsafe { (*arena).next };
+    }
+    let mut handle = lock(&EMERGENCY);
+    matches(&handle).then(|| f(&mut handle))
+}
+
+/// Runs `f` on the shared arena and then on every thread arena, one lock at
+/// a time.
+pub(crate) fn for_each_arena(mut f: impl FnMut(&mut HeapHandle)) {
+    f(&mut lock_arena(heap_anchor()));
+    let mut arena = ARENAS.load(Ordering::Acquire);
+    while !arena.is_null() {
+        f(&mut lock_arena(unsafe { &(*arena).handle }));
+        arena = unsafe { (*arena).next };
+    }
+}
+
+#[cfg(test)]
+mod arena_tests {
+    use std::{
+        ffi::c_void,
+        sync::{mpsc, Arc, Barrier},
+        thread,
+        time::Instant,
+    };
+
+    use crate::{free, heap_anchor, malloc, malloc_usable_size, try_free};
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn threads_get_their_own_arena() {
+        use super::{current_arena, SHARED};
+
+        let here = current_arena() as usize;
+        let there = thread::spawn(|| current_arena() as usize).join().unwrap();
+        assert_ne!(here, SHARED as usize);
+        assert_ne!(there, SHARED as usize);
+        assert_ne!(here, there);
+    }
+
+    #[test]
+    fn shared_arena_is_created_once() {
+        let start = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let start = Arc::clone(&start);
+                thread::spawn(move || {
+                    // Every thread asks for the shared arena at the same time.
+                    start.wait();
+                    heap_anchor() as *const _ as usize
+                })
+            })
+            .collect();
+        let anchors: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(anchors.iter().all(|&anchor| anchor == heap_anchor() as *const _ as usize));
+    }
+
+    #[test]
+    fn free_from_another_thread() {
+        let (sender, receiver) = mpsc::channel();
+        let (done, finished) = mpsc::channel::<()>();
+        let owner = thread::spawn(move || {
+            let ptrs: Vec<usize> = (0..64).map(|i| malloc(8 + i * 24) as usize).collect();
+            sender.send(ptrs).unwrap();
+            // Keep the arena claimed until the other thread is done with it.
+            finished.recv().unwrap();
+        });
+
+        for ptr in receiver.recv().unwrap() {
+            let ptr = ptr as *mut c_void;
+            assert!(malloc_usable_size(ptr) > 0);
+            unsafe { free(ptr) };
+            assert!(unsafe { try_free(ptr) }.is_err());
+        }
+        done.send(()).unwrap();
+        owner.join().unwrap();
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn arena_throughput() {
+        const OPS: usize = 200_000;
+        for threads in [1, 2, 4, 8] {
+            let start = Instant::now();
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    thread::spawn(|| {
+                        for i in 0..OPS {
+                            let ptr = malloc(16 + i % 200);
+                            unsafe { free(ptr) };
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            println!("{threads} threads: {:.0} ops/s", (threads * OPS) as f64 / elapsed);
+        }
+    }
+}
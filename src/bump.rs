@@ -0,0 +1,193 @@
+//! Bump allocation for short-lived objects.
+//!
+//! A [`BumpArena`] hands out memory by moving a cursor through mapped chunks
+//! and never frees anything on its own. Everything goes at once, on
+//! [`BumpArena::reset`] or when the arena is dropped.
+
+use core::{alloc::Layout, ffi::c_void, mem, ptr};
+
+use crate::{
+    align,
+    mmap::{mem_map, mem_unmap, MMAP_NO_FLAGS, MMAP_PROT_READ_WRITE},
+    MIN_ALIGN, SMALL_HEAP_ALLOCATION_SIZE,
+};
+
+/// Header at the start of every chunk.
+#[repr(C, align(16))]
+struct Chunk {
+    /// The chunk that was current before this one.
+    previous: *mut Chunk,
+    /// Length of the mapping, header included.
+    size: usize,
+}
+
+/// An arena that allocates by bumping a pointer and frees everything at once.
+///
+/// ```
+/// use my_alloc::BumpArena;
+///
+/// let mut arena = BumpArena::new();
+/// let ptr = arena.alloc(64) as *mut u8;
+/// unsafe { ptr.write_bytes(0, 64) };
+/// arena.reset();
+/// ```
+pub struct BumpArena {
+    /// Chunk being bumped through, linked to the ones filled before it.
+    chunk: *mut Chunk,
+    /// Address of the next free byte in `chunk`.
+    cursor: usize,
+    chunk_size: usize,
+}
+
+unsafe impl Send for BumpArena {}
+
+impl BumpArena {
+    /// Creates an arena that maps memory in 128 KiB chunks. Nothing is
+    /// mapped until the first allocation.
+    pub const fn new() -> Self {
+        Self::with_chunk_size(SMALL_HEAP_ALLOCATION_SIZE)
+    }
+
+    /// Creates an arena that maps memory in chunks of `bytes`. Requests
+    /// that do not fit in a chunk get one of their own.
+    pub const fn with_chunk_size(bytes: usize) -> Self {
+        Self {
+            chunk: ptr::null_mut(),
+            cursor: 0,
+            chunk_size: bytes,
+        }
+    }
+
+    /// Allocates `size` bytes aligned to 16. Returns null when the system is
+    /// out of memory.
+    pub fn alloc(&mut self, size: usize) -> *mut c_void {
+        match Layout::from_size_align(size, MIN_ALIGN) {
+            Ok(layout) => self.alloc_layout(layout),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    /// Allocates a block satisfying `layout`. Returns null when the system
+    /// is out of memory.
+    pub fn alloc_layout(&mut self, layout: Layout) -> *mut c_void {
+        if let Some(ptr) = self.bump(layout) {
+            return ptr;
+        }
+        let Some(needed) = layout.size().checked_add(Chunk::size() + layout.align()) else {
+            return ptr::null_mut();
+        };
+        let size = needed.max(self.chunk_size);
+        let Ok(memory) = mem_map(size, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS) else {
+            return ptr::null_mut();
+        };
+        let chunk = memory as *mut Chunk;
+        unsafe {
+            chunk.write(Chunk {
+                previous: self.chunk,
+                size,
+            })
+        };
+        self.chunk = chunk;
+        self.cursor = chunk as usize + Chunk::size();
+        self.bump(layout).unwrap_or(ptr::null_mut())
+    }
+
+    /// Carves `layout` out of the current chunk, if it fits.
+    fn bump(&mut self, layout: Layout) -> Option<*mut c_void> {
+        if self.chunk.is_null() {
+            return None;
+        }
+        let start = align(layout.align(), self.cursor)?;
+        let end = start.checked_add(layout.size())?;
+        if end > self.chunk as usize + unsafe { (*self.chunk).size } {
+            return None;
+        }
+        self.cursor = end;
+        Some(start as *mut c_void)
+    }
+
+    /// Does nothing: bump allocations are only released all together, by
+    /// [`reset`](Self::reset) or by dropping the arena.
+    pub fn free(&mut self, _ptr: *mut c_void) {}
+
+    /// Releases every allocation at once. The first chunk stays mapped for
+    /// the allocations to come, the others are unmapped.
+    pub fn reset(&mut self) {
+        if self.chunk.is_null() {
+            return;
+        }
+        unsafe {
+            while !(*self.chunk).previous.is_null() {
+                let previous = (*self.chunk).previous;
+                let _ = mem_unmap(self.chunk as *const c_void, (*self.chunk).size);
+                self.chunk = previous;
+            }
+        }
+        self.cursor = self.chunk as usize + Chunk::size();
+    }
+}
+
+impl Chunk {
+    fn size() -> usize {
+        mem::size_of::<Chunk>()
+    }
+}
+
+impl Default for BumpArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for BumpArena {
+    fn drop(&mut self) {
+        self.reset();
+        if !self.chunk.is_null() {
+            let _ = mem_unmap(self.chunk as *const c_void, unsafe { (*self.chunk).size });
+        }
+    }
+}
+
+#[cfg(test)]
+mod bump_tests {
+    use std::alloc::Layout;
+
+    use super::BumpArena;
+    use crate::mmap::LIVE_MAPS;
+
+    #[test]
+    fn reset_rewinds_and_reuses_memory() {
+        let before = LIVE_MAPS.get();
+        let mut arena = BumpArena::with_chunk_size(16 * 1024);
+        let first = arena.alloc(24);
+        let mut last = first;
+        for i in 0..5000 {
+            let ptr = arena.alloc(24);
+            assert_eq!(ptr as usize % 16, 0);
+            unsafe { (ptr as *mut u8).write_bytes(i as u8, 24) };
+            last = ptr;
+        }
+        assert_ne!(last, first);
+        assert!(LIVE_MAPS.get() - before > 1);
+
+        arena.reset();
+        assert_eq!(LIVE_MAPS.get() - before, 1);
+        assert_eq!(arena.alloc(24), first);
+        for _ in 0..5000 {
+            unsafe { (arena.alloc(24) as *mut u8).write_bytes(0xee, 24) };
+        }
+        drop(arena);
+        assert_eq!(LIVE_MAPS.get(), before);
+    }
+
+    #[test]
+    fn oversized_and_aligned_requests() {
+        let mut arena = BumpArena::with_chunk_size(4096);
+        let big = arena.alloc(100_000) as *mut u8;
+        unsafe { big.write_bytes(1, 100_000) };
+        let layout = Layout::from_size_align(64, 1024).unwrap();
+        let aligned = arena.alloc_layout(layout);
+        assert_eq!(aligned as usize % 1024, 0);
+        assert!(arena.alloc(usize::MAX - 8).is_null());
+    }
+}
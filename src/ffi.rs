@@ -0,0 +1,76 @@
+//! C ABI entry points, so the crate can stand in for the system allocator
+//! (for instance through `LD_PRELOAD`). Only built with the `ffi` feature,
+//! since exporting these symbols replaces `malloc` for the whole process.
+
+use core::{ffi::c_void, mem, ptr};
+
+const EINVAL: i32 = 22;
+const ENOMEM: i32 = 12;
+
+/// # Safety
+///
+/// Same contract as C `malloc`.
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    crate::malloc(size)
+}
+
+/// # Safety
+///
+/// Same contract as C `free`. Pointers this allocator does not recognise
+/// are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        let _ = crate::try_free(ptr);
+    }
+}
+
+/// # Safety
+///
+/// Same contract as C `calloc`.
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+    crate::calloc(count, size)
+}
+
+/// # Safety
+///
+/// Same contract as C `realloc`. Pointers this allocator does not recognise
+/// are left untouched and get null back, as when memory runs out.
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    crate::try_realloc(ptr, size).unwrap_or(ptr::null_mut())
+}
+
+/// # Safety
+///
+/// Same contract as C `reallocarray`, and as [`realloc`] for pointers this
+/// allocator does not recognise.
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(ptr: *mut c_void, count: usize, size: usize) -> *mut c_void {
+    match count.checked_mul(size) {
+        Some(total) => realloc(ptr, total),
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// Same contract as C `posix_memalign`: `memptr` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: usize,
+    size: usize,
+) -> i32 {
+    if !alignment.is_power_of_two() || !alignment.is_multiple_of(mem::size_of::<*mut c_void>()) {
+        return EINVAL;
+    }
+    let allocation = crate::aligned_alloc(alignment, size);
+    if allocation.is_null() {
+        return ENOMEM;
+    }
+    ptr::write(memptr, allocation);
+    0
+}
@@ -0,0 +1,70 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ffi::c_void,
+    ptr,
+};
+
+use crate::{allocate, deallocate, realloc, MIN_ALIGN};
+
+/// Zero-sized handle that routes Rust's global allocations through this crate.
+///
+/// ```no_run
+/// use my_alloc::MurymiAlloc;
+///
+/// #[global_allocator]
+/// static A: MurymiAlloc = MurymiAlloc;
+/// ```
+pub struct MurymiAlloc;
+
+unsafe impl GlobalAlloc for MurymiAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        allocate(layout) as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // `dealloc` has no way to report failure, and panicking inside the
+        // global allocator is not an option.
+        let _ = deallocate(ptr as *mut c_void, layout);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN {
+            return realloc(ptr as *mut c_void, new_size) as *mut u8;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod global_tests {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    use super::MurymiAlloc;
+
+    #[test]
+    fn over_aligned_layouts() {
+        for align in [16, 64, 256] {
+            let layout = Layout::from_size_align(40, align).unwrap();
+            unsafe {
+                let ptr = MurymiAlloc.alloc(layout);
+                assert_eq!(ptr as usize % align, 0);
+                ptr.write_bytes(0xab, 40);
+                MurymiAlloc.dealloc(ptr, layout);
+            }
+        }
+    }
+}
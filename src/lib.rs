@@ -0,0 +1,5651 @@
+//! A small `mmap`-backed heap allocator.
+//!
+//! Requests are grouped into tiny, small and large size classes. Tiny and
+//! small requests are carved out of shared heaps, large requests get a
+//! mapping of their own.
+//!
+//! Without the default `std` feature the crate is `no_std`: every thread
+//! shares one arena behind a spinlock. Build it with
+//! `cargo build --no-default-features` and test it with
+//! `cargo test --lib --no-default-features`; `scripts/check.sh` runs both.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+
+use core::{
+    alloc::Layout,
+    error::Error,
+    ffi::c_void,
+    fmt, iter,
+    mem::{self, discriminant},
+    panic::Location,
+    ptr::{self, addr_of_mut, null, NonNull},
+    slice,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+#[cfg(target_os = "linux")]
+use mmap::{mem_bind, mem_remap};
+use mmap::{
+    get_page_size, mem_discard, mem_map, mem_protect, mem_unmap, MMAP_HUGE_PAGES, MMAP_NO_FLAGS,
+    MMAP_POPULATE, MMAP_PROT_NONE, MMAP_PROT_READ_WRITE,
+};
+#[cfg(feature = "std")]
+use std::{collections::HashMap, sync::OnceLock};
+#[cfg(not(feature = "std"))]
+use sync::Mutex;
+#[cfg(feature = "size-map")]
+use sync::PoisonError;
+
+mod allocator;
+mod arena;
+mod bump;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod global;
+mod mmap;
+#[cfg(feature = "size-map")]
+mod size_map;
+mod sync;
+
+#[cfg(feature = "std")]
+pub use allocator::SharedAllocator;
+pub use allocator::{Allocator, AllocatorConfig};
+pub use bump::BumpArena;
+pub use global::MurymiAlloc;
+#[cfg(feature = "std")]
+pub use sync::MutexLock;
+pub use sync::{DefaultLock, HeapLock, NoLock, SpinLock};
+
+const PAGE_SIZE: usize = 4096;
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Alignment of every block's data, enough for `u128` and SIMD vectors.
+/// Headers and block sizes are kept multiples of it.
+const MIN_ALIGN: usize = 16;
+/// Smallest free block a split may leave behind; anything less is handed out
+/// with the block instead.
+const MIN_REMAINDER: usize = 2 * MIN_ALIGN;
+/// Free blocks are binned by power-of-two size class, starting at
+/// `MIN_ALIGN`; the last bin takes everything larger.
+const BIN_COUNT: usize = 16;
+/// Freed large mappings kept around per arena for reuse.
+const LARGE_CACHE_ENTRIES: usize = 8;
+const LARGE_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Upper bound on the bytes each arena keeps in its large-mapping cache.
+static LARGE_CACHE_CAP: AtomicUsize = AtomicUsize::new(LARGE_CACHE_BYTES);
+/// Settings of the arenas behind the global entry points, see
+/// [`default_config`].
+static TRIM_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+static MIN_SPLIT: AtomicUsize = AtomicUsize::new(MIN_REMAINDER);
+static LARGE_CUTOFF: AtomicUsize = AtomicUsize::new(SMALL_HEAP_ALLOCATION_SIZE);
+static QUARANTINE: AtomicUsize = AtomicUsize::new(0);
+static ROUNDING_CAP: AtomicUsize = AtomicUsize::new(0);
+/// Most freed blocks an arena holds back from reuse, see [`set_quarantine`].
+const QUARANTINE_SLOTS: usize = 64;
+/// Bytes reserved after each allocation for the overflow canary.
+const REDZONE: usize = if cfg!(feature = "redzone") { 16 } else { 0 };
+const CANARY: u32 = 0xDEADBEEF;
+/// Fill patterns of the `poison` feature.
+const FRESH_BYTE: u8 = 0xAA;
+const FREED_BYTE: u8 = 0xDD;
+/// Whether new large allocations end at an inaccessible page.
+static GUARD_PAGES: AtomicBool = AtomicBool::new(false);
+/// Whether new large allocations ask for huge pages.
+static HUGE_PAGES: AtomicBool = AtomicBool::new(false);
+/// Whether new large allocations are faulted in while they are mapped.
+static PREFAULT: AtomicBool = AtomicBool::new(false);
+/// Whether `trim` discards free pages instead of unmapping them.
+static TRIM_DISCARDS: AtomicBool = AtomicBool::new(false);
+/// Whether a failed mapping is retried once the arena's own spare memory is
+/// unmapped.
+static TRIM_ON_OOM: AtomicBool = AtomicBool::new(false);
+/// Whether free blocks are picked best-fit rather than first-fit.
+static BEST_FIT: AtomicBool = AtomicBool::new(false);
+const TINY_HEAP_ALLOCATION_SIZE: usize = 4 * PAGE_SIZE;
+const SMALL_HEAP_ALLOCATION_SIZE: usize = 32 * PAGE_SIZE;
+/// Blocks of up to `1 / BLOCKS_PER_HEAP` of a tiny or small heap's size are
+/// carved out of heaps of that group.
+const BLOCKS_PER_HEAP: usize = 128;
+/// Sizes of the emergency pool behind [`try_malloc_emergency`]: a tiny heap
+/// and a 64 KiB small heap, serving requests of up to 512 bytes, none of
+/// which is ever unmapped.
+const EMERGENCY_CONFIG: AllocatorConfig = AllocatorConfig::new()
+    .small_heap_size(16 * PAGE_SIZE)
+    .large_threshold(16 * PAGE_SIZE / BLOCKS_PER_HEAP)
+    .trim_threshold(usize::MAX);
+/// The emergency pool gets another heap for a size class once less than this
+/// is left free in it.
+const EMERGENCY_LOW: usize = PAGE_SIZE;
+
+#[derive(Debug, PartialEq)]
+#[repr(u8)]
+#[repr(C)]
+enum HeapGroup {
+    Tiny(usize),
+    Small(usize),
+    Large(usize),
+}
+
+impl HeapGroup {
+    /// Group of a block of `value` bytes: up to and including the tiny block
+    /// size is tiny, up to and including the small block size is small, and
+    /// anything bigger gets an exact-size heap. Callers pass the size after
+    /// `MIN_ALIGN` rounding, which both boundaries are multiples of.
+    fn new(value: usize, config: &AllocatorConfig) -> Self {
+        if value <= config.tiny_block_size() {
+            Self::Tiny(value)
+        } else if value <= config.small_block_size() {
+            Self::Small(value)
+        } else {
+            Self::Large(value)
+        }
+    }
+
+    /// Length of a heap's mapping, in whole system pages so that none of the
+    /// mapping goes to waste. The block size boundaries stay those of the
+    /// configured sizes.
+    fn alloc_size(&self, config: &AllocatorConfig) -> usize {
+        let size = match self {
+            HeapGroup::Tiny(_) => config.tiny_heap_size,
+            HeapGroup::Small(_) => config.small_heap_size,
+            HeapGroup::Large(v) => v + mem::size_of::<Block>() + mem::size_of::<Heap>(),
+        };
+        size.next_multiple_of(get_page_size())
+    }
+}
+
+#[derive(Debug)]
+#[repr(C, align(16))]
+struct Heap {
+    group: HeapGroup,
+    next: *mut Heap,
+    previous: *mut Heap,
+    total_size: usize,
+    free_size: usize,
+    block_count: usize,
+    /// The block new ones are appended after, null while there is none.
+    last: *mut Block,
+    /// Free blocks of this heap by size class, most recently freed first.
+    bins: [*mut Block; BIN_COUNT],
+}
+
+unsafe impl Send for Heap{}
+unsafe  impl Sync for Heap{}
+
+impl Heap {
+    fn new(size: usize, config: &AllocatorConfig) -> Self {
+        let gp = HeapGroup::new(size, config);
+        let size = gp.alloc_size(config);
+        Self {
+            next: ptr::null_mut(),
+            previous: ptr::null_mut(),
+            total_size: size,
+            free_size: size - Self::size(),
+            group: gp,
+            block_count: 0,
+            last: ptr::null_mut(),
+            bins: [ptr::null_mut(); BIN_COUNT],
+        }
+    }
+
+    fn size() -> usize {
+        mem::size_of::<Self>()
+    }
+
+    /// Takes `bytes` out of the free space, refusing instead of wrapping
+    /// around when the heap does not have them.
+    fn take(&mut self, bytes: usize) -> bool {
+        match self.free_size.checked_sub(bytes) {
+            Some(left) => {
+                self.free_size = left;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// [`Block`] flag: the block is in a free list or the large cache.
+const FREE: usize = 1;
+/// [`Block`] flag: a large block followed by a guard page; its mapping
+/// starts at the page holding the header.
+const GUARDED: usize = 1 << 1;
+/// [`Block`] flag: a large block whose header sits past the start of its
+/// mapping, by the lead stored in the word right before the header.
+const LEAD: usize = 1 << 2;
+/// The low bits of a [`Block`]'s size that hold its flags. Data sizes are
+/// multiples of [`MIN_ALIGN`], which leaves them clear.
+const FLAGS: usize = MIN_ALIGN - 1;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct Block {
+    next: *const Block,
+    previous: *const Block,
+    /// Usable bytes, with [`FREE`], [`GUARDED`] and [`LEAD`] in the low bits.
+    size_flags: usize,
+    stamp: Stamp,
+    /// Where the allocation was made.
+    #[cfg(feature = "leak-check")]
+    site: Option<&'static Location<'static>>,
+}
+
+/// The last word of a [`Block`] header, of which a handed out block only
+/// needs its stamps and a free one only its free-list link.
+#[derive(Clone, Copy)]
+#[repr(C)]
+union Stamp {
+    live: Live,
+    /// Next block in the owning heap's free list.
+    free_next: *mut Block,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Live {
+    /// Stamp of the arena's free count at hand-out, which a [`TrackedPtr`]
+    /// must match.
+    generation: u16,
+    /// Category given through [`malloc_tagged`], 0 for everything else.
+    tag: u32,
+}
+
+impl Block {
+    fn new(size: usize) -> Self {
+        debug_assert_eq!(size & FLAGS, 0);
+        Self {
+            next: null(),
+            previous: null(),
+            size_flags: size,
+            // Writes the whole word, so either half reads initialized bytes.
+            stamp: Stamp { free_next: ptr::null_mut() },
+            #[cfg(feature = "leak-check")]
+            site: None,
+        }
+    }
+
+    fn size() -> usize {
+        mem::size_of::<Block>()
+    }
+
+    fn data_size(&self) -> usize {
+        self.size_flags & !FLAGS
+    }
+
+    fn set_data_size(&mut self, size: usize) {
+        debug_assert_eq!(size & FLAGS, 0);
+        self.size_flags = size | self.size_flags & FLAGS;
+    }
+
+    fn is_free(&self) -> bool {
+        self.size_flags & FREE != 0
+    }
+
+    fn set_free(&mut self, free: bool) {
+        self.set_flag(FREE, free);
+    }
+
+    fn is_guarded(&self) -> bool {
+        self.size_flags & GUARDED != 0
+    }
+
+    fn set_guarded(&mut self, guarded: bool) {
+        self.set_flag(GUARDED, guarded);
+    }
+
+    fn set_flag(&mut self, flag: usize, on: bool) {
+        match on {
+            true => self.size_flags |= flag,
+            false => self.size_flags &= !flag,
+        }
+    }
+
+    fn generation(&self) -> u16 {
+        unsafe { self.stamp.live.generation }
+    }
+
+    fn tag(&self) -> u32 {
+        unsafe { self.stamp.live.tag }
+    }
+
+    /// Stamps a block being handed out. Only touches the stamps, so the rest
+    /// of the word stays initialized.
+    fn set_stamps(&mut self, generation: u16, tag: u32) {
+        self.stamp.live.generation = generation;
+        self.stamp.live.tag = tag;
+    }
+
+    /// Only meaningful while the block is free.
+    fn free_next(&self) -> *mut Block {
+        unsafe { self.stamp.free_next }
+    }
+
+    fn set_free_next(&mut self, next: *mut Block) {
+        self.stamp.free_next = next;
+    }
+}
+
+/// Bytes between the start of a large block's mapping and its header, left
+/// over from aligning its data.
+fn block_lead(block: *const Block) -> usize {
+    unsafe {
+        match (*block).size_flags & LEAD {
+            0 => 0,
+            _ => block.cast::<usize>().sub(1).read(),
+        }
+    }
+}
+
+/// The data of the block at `$ptr`, right after its header.
+macro_rules! block_shift {
+    ($ptr: expr) => {{
+        let block: *const Block = $ptr;
+        block.cast::<u8>().cast_mut().add(mem::size_of::<Block>())
+    }};
+}
+
+/// The header of the block whose data starts at `$ptr`.
+macro_rules! block_unshift {
+    ($ptr: expr) => {{
+        let data: *const u8 = ($ptr).cast::<u8>();
+        data.sub(mem::size_of::<Block>()).cast::<Block>().cast_mut()
+    }};
+}
+
+/// Where the first block of the heap at `$ptr` goes, right after its header.
+macro_rules! heap_shift {
+    ($ptr: expr) => {{
+        let heap: *const Heap = $ptr;
+        heap.cast::<u8>().cast_mut().add(mem::size_of::<Heap>())
+    }};
+}
+
+/// The header of the heap whose first block is at `$ptr`.
+#[allow(unused_macros)]
+macro_rules! heap_unshift {
+    ($ptr: expr) => {{
+        let first: *const u8 = ($ptr).cast::<u8>();
+        first.sub(mem::size_of::<Heap>()).cast::<Heap>().cast_mut()
+    }};
+}
+
+/// Why [`try_free`] refused to release a pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeError {
+    /// The pointer was not handed out by this allocator.
+    InvalidPointer,
+    /// The pointer refers to a block that is already free.
+    DoubleFree,
+    /// Returning the memory to the OS failed.
+    Unmapped,
+    /// Something wrote past the end of the allocation at this address.
+    Corrupted(*const c_void),
+    /// The [`TrackedPtr`] outlived its allocation: the memory was freed and
+    /// handed out again since.
+    Stale,
+    /// The size given to [`free_sized`] is more than the allocation holds.
+    WrongSize,
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreeError::InvalidPointer => f.write_str("invalid pointer"),
+            FreeError::DoubleFree => f.write_str("double free detected"),
+            FreeError::Unmapped => f.write_str("failed to unmap memory"),
+            FreeError::Corrupted(ptr) => write!(f, "heap corruption detected at {ptr:p}"),
+            FreeError::Stale => f.write_str("stale pointer to reused memory"),
+            FreeError::WrongSize => f.write_str("size does not match the allocation"),
+        }
+    }
+}
+
+impl Error for FreeError {}
+
+/// Memory use of one size group, see [`HeapStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GroupStats {
+    /// Heaps, or standalone mappings for large allocations.
+    pub mappings: usize,
+    pub mapped_bytes: usize,
+    /// Mapped bytes not handed out, headers of free blocks included.
+    pub free_bytes: usize,
+    pub live_blocks: usize,
+}
+
+/// A snapshot of the allocator's memory use, returned by [`stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub tiny: GroupStats,
+    pub small: GroupStats,
+    /// Exact-size heaps, standalone mappings and the large-mapping caches.
+    pub large: GroupStats,
+}
+
+impl HeapStats {
+    /// The sum over all groups.
+    pub fn total(&self) -> GroupStats {
+        let groups = [self.tiny, self.small, self.large];
+        GroupStats {
+            mappings: groups.iter().map(|g| g.mappings).sum(),
+            mapped_bytes: groups.iter().map(|g| g.mapped_bytes).sum(),
+            free_bytes: groups.iter().map(|g| g.free_bytes).sum(),
+            live_blocks: groups.iter().map(|g| g.live_blocks).sum(),
+        }
+    }
+}
+
+/// One block as seen by [`for_each_block`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// Where the block's data starts, as returned by [`malloc`].
+    pub ptr: *const c_void,
+    /// Usable bytes.
+    pub size: usize,
+    pub free: bool,
+}
+
+struct HeapHandle{
+    heap: *mut Heap,
+    /// Total size of the heaps in `heap` that hold no live block, weighed
+    /// against the trim threshold on every free.
+    idle_bytes: usize,
+    /// Standalone mappings for requests above the large threshold, chained
+    /// through their `Block` headers.
+    large: *mut Block,
+    /// Freed large mappings, most recently freed first.
+    large_cache: *mut Block,
+    large_cache_len: usize,
+    large_cache_bytes: usize,
+    /// Call site of the public entry point using the handle, recorded in
+    /// every block it hands out.
+    site: Option<&'static Location<'static>>,
+    /// Tag written into every block handed out; only [`malloc_tagged`] sets
+    /// it, and only for the duration of its call.
+    tag: u32,
+    /// Whether the last large block handed out is a new mapping, which the
+    /// OS fills with zeroes.
+    fresh: bool,
+    config: AllocatorConfig,
+    /// Bytes held by the blocks handed out and not yet freed, padding and
+    /// redzones included.
+    live_bytes: usize,
+    /// The most `live_bytes` has been since the handle was created or
+    /// [`reset_high_watermark`] last ran.
+    peak_bytes: usize,
+    /// Live blocks by the size class of their usable bytes, see
+    /// [`size_class_histogram`].
+    class_counts: [usize; BIN_COUNT],
+    /// Stamped into every block handed out and moved on by every free, so a
+    /// block reusing the address of a freed one carries another stamp.
+    generation: u16,
+    /// Freed blocks held back from reuse, oldest first from
+    /// `quarantine_start`, wrapping around.
+    quarantine: [*mut Block; QUARANTINE_SLOTS],
+    quarantine_start: usize,
+    quarantine_len: usize,
+}
+
+unsafe impl Send for HeapHandle{}
+unsafe impl Sync for HeapHandle{}
+
+impl HeapHandle {
+    const fn new() -> Self {
+        Self {
+            heap: ptr::null_mut(),
+            idle_bytes: 0,
+            large: ptr::null_mut(),
+            large_cache: ptr::null_mut(),
+            large_cache_len: 0,
+            large_cache_bytes: 0,
+            site: None,
+            tag: 0,
+            fresh: false,
+            config: AllocatorConfig::new(),
+            live_bytes: 0,
+            peak_bytes: 0,
+            class_counts: [0; BIN_COUNT],
+            generation: 0,
+            quarantine: [ptr::null_mut(); QUARANTINE_SLOTS],
+            quarantine_start: 0,
+            quarantine_len: 0,
+        }
+    }
+
+    const fn with_config(config: AllocatorConfig) -> Self {
+        Self { config, ..Self::new() }
+    }
+}
+
+/// A heap list behind the arenas' lock.
+type ArenaLock = <DefaultLock as HeapLock>::Lock<HeapHandle>;
+
+/// The shared arena, for threads that cannot use one of their own. Created
+/// on first use.
+#[cfg(feature = "std")]
+fn heap_anchor() -> &'static ArenaLock {
+    static HEAP_ANCHOR: OnceLock<ArenaLock> = OnceLock::new();
+    HEAP_ANCHOR.get_or_init(|| DefaultLock::new(HeapHandle::new()))
+}
+
+/// The one arena every thread shares without `std`.
+#[cfg(not(feature = "std"))]
+fn heap_anchor() -> &'static ArenaLock {
+    static HEAP_ANCHOR: ArenaLock = Mutex::new(HeapHandle::new());
+    &HEAP_ANCHOR
+}
+
+/// Locks a heap list. `heap_realloc` only panics before it touches any heap
+/// state, so a poisoned lock still guards a consistent heap list.
+fn lock(handle: &ArenaLock) -> <DefaultLock as HeapLock>::Guard<'_, HeapHandle> {
+    DefaultLock::lock(handle)
+}
+/// Maps a new heap for blocks of `size`, or returns `None` when the OS has
+/// no memory left.
+fn create_heap(size: usize, config: &AllocatorConfig) -> Option<*mut Heap> {
+    let header = Heap::new(size, config);
+    let ptr = mem_map(header.total_size, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).ok()? as *mut Heap;
+    unsafe {
+        ptr.write(header);
+    }
+    Some(ptr)
+}
+
+/// Maps a heap for `count` blocks of `size` bytes, bigger than the usual
+/// size of its group when that is too small to hold them all.
+#[cfg(feature = "std")]
+fn create_batch_heap(size: usize, count: usize, config: &AllocatorConfig) -> Option<*mut Heap> {
+    let mut header = Heap::new(size, config);
+    let needed = count.checked_mul(size + Block::size())?.checked_add(Heap::size())?;
+    let length = align(get_page_size(), needed)?.max(header.total_size);
+    header.total_size = length;
+    header.free_size = length - Heap::size();
+    let ptr = mem_map(length, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).ok()? as *mut Heap;
+    unsafe {
+        ptr.write(header);
+    }
+    Some(ptr)
+}
+
+/// Puts a new heap at the front of the heap list, where `get_heap` looks
+/// first.
+fn link_heap(handle: &mut HeapHandle, heap: *mut Heap) {
+    if heap_is_empty(heap) {
+        handle.idle_bytes += unsafe { (*heap).total_size };
+    }
+    unsafe {
+        (*heap).next = handle.heap;
+        if !handle.heap.is_null() {
+            (*handle.heap).previous = heap;
+        }
+    }
+    handle.heap = heap;
+}
+
+/// Maps a heap into the emergency pool for each size class that has less
+/// than `EMERGENCY_LOW` bytes free. Returns false when the OS has no memory
+/// for one.
+fn refill_emergency(handle: &mut HeapHandle) -> bool {
+    let mut stats = HeapStats::default();
+    heap_stats(handle, &mut stats);
+    let small = handle.config.tiny_block_size() + 1;
+    let tiny_ok = stats.tiny.free_bytes >= EMERGENCY_LOW || heap_reserve(handle, 1, 1);
+    let small_ok = stats.small.free_bytes >= EMERGENCY_LOW || heap_reserve(handle, small, 1);
+    tiny_ok && small_ok
+}
+
+/// Maps enough new heaps to hold `count` blocks of `size` bytes. Returns
+/// false when blocks of `size` get a mapping of their own, or when the OS
+/// runs out of memory; the heaps mapped until then stay.
+fn heap_reserve(handle: &mut HeapHandle, size: usize, count: usize) -> bool {
+    let Some(size) = size.max(1).checked_add(REDZONE).and_then(|size| align(MIN_ALIGN, size))
+    else {
+        return false;
+    };
+    if size > handle.config.large_threshold {
+        return false;
+    }
+    let config = handle.config;
+    let header = Heap::new(size, &config);
+    // An exact-size heap gives all of itself to its first block.
+    let per_heap = match header.group {
+        HeapGroup::Large(_) => 1,
+        _ => header.free_size / (size + Block::size()),
+    };
+    for _ in 0..count.div_ceil(per_heap) {
+        let Some(heap) = create_heap(size, &config) else {
+            return false;
+        };
+        link_heap(handle, heap);
+    }
+    true
+}
+
+/// Whether a block header at `block` would lie within `heap`, past its
+/// header and clear of its end, on the alignment every header has.
+fn in_heap(heap: *const Heap, block: *const Block) -> bool {
+    let addr = block as usize;
+    let start = unsafe { heap_shift!(heap) } as usize;
+    let end = heap as usize + unsafe { (*heap).total_size };
+    addr >= start && addr.is_multiple_of(MIN_ALIGN) && addr + Block::size() <= end
+}
+
+/// The blocks of `heap` in address order. The walk ends early, instead of
+/// running off into wild memory, at a `next` link that leaves the heap or
+/// does not lead past the current block, as only corruption makes one.
+fn blocks_of(heap: *const Heap) -> impl Iterator<Item = *mut Block> {
+    // Past the header of a heap without blocks lies memory no block was
+    // ever written to.
+    let first = match unsafe { (*heap).block_count } > 0 {
+        true => Some(unsafe { heap_shift!(heap).cast::<Block>() }),
+        false => None,
+    };
+    iter::successors(first, move |&block| {
+        let next = unsafe { (*block).next };
+        (next > block.cast_const() && in_heap(heap, next)).then_some(next.cast_mut())
+    })
+}
+
+/// The last block of `heap`, which must hold at least one.
+fn get_last_block(heap: *const Heap) -> *mut Block {
+    let last = unsafe { (*heap).last };
+    debug_assert!(!last.is_null());
+    last
+}
+
+/// Rounds `from` up to a multiple of `to`, a power of two. Returns `None`
+/// instead of wrapping around when the result does not fit in a `usize`.
+fn align(to: usize, from: usize) -> Option<usize> {
+    Some(from.checked_add(to - 1)? & !(to - 1))
+}
+
+/// Size class of a block holding `size` bytes: bin `i` covers
+/// `[MIN_ALIGN << i, MIN_ALIGN << (i + 1))`.
+fn bin_index(size: usize) -> usize {
+    let class = (size.max(MIN_ALIGN) / MIN_ALIGN).ilog2() as usize;
+    class.min(BIN_COUNT - 1)
+}
+
+fn get_free_block(size: usize, heap: *const Heap) -> Option<*mut Block> {
+    let best_fit = BEST_FIT.load(Ordering::Relaxed);
+    unsafe {
+        // The request's own bin may hold blocks that are too small, every
+        // bin above it only holds blocks that fit. Bins hold increasing
+        // sizes, so the tightest fit is in the first bin with any fit.
+        for bin in bin_index(size)..BIN_COUNT {
+            let mut found: Option<*mut Block> = None;
+            let mut curr_block = (*heap).bins[bin];
+            while !curr_block.is_null() {
+                if !in_heap(heap, curr_block) {
+                    // A corrupted link; the bin is unusable past it.
+                    break;
+                }
+                let data_size = curr_block.read().data_size();
+                if data_size >= size && found.is_none_or(|best| data_size < (*best).data_size()) {
+                    if !best_fit || data_size == size {
+                        return Some(curr_block);
+                    }
+                    found = Some(curr_block);
+                }
+                curr_block = curr_block.read().free_next();
+            }
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+}
+
+/// Files a free block under its size class. Blocks must be unlinked before
+/// their `data_size` changes and pushed again afterwards.
+fn push_free_block(heap: *mut Heap, block: *mut Block) {
+    unsafe {
+        let bin = bin_index((*block).data_size());
+        (*block).set_free_next((*heap).bins[bin]);
+        (*heap).bins[bin] = block;
+    }
+}
+
+fn unlink_free_block(heap: *mut Heap, block: *mut Block) {
+    unsafe {
+        let mut link = addr_of_mut!((*heap).bins[bin_index((*block).data_size())]);
+        while !(*link).is_null() {
+            if *link == block {
+                *link = (*block).free_next();
+                return;
+            }
+            link = addr_of_mut!((**link).stamp.free_next);
+        }
+    }
+}
+
+fn get_heap(size: usize, head: *mut Heap, config: &AllocatorConfig) -> Option<*mut Heap> {
+    let s = size;
+    if head.is_null() {
+        return None;
+    }
+    let heap_group = HeapGroup::new(s, config);
+    let mut first_heap = head;
+    loop {
+        if discriminant(&unsafe { first_heap.read() }.group) == discriminant(&heap_group)
+            && has_room(first_heap, s)
+        {
+            break Some(first_heap);
+        }
+        first_heap = unsafe { first_heap.read() }.next;
+        if first_heap.is_null() {
+            break None;
+        }
+    }
+}
+
+/// Whether `heap` holds `size` bytes in one piece: in a free block, or past
+/// its last block along with a header. Its free bytes alone do not tell, as
+/// they may be scattered over holes too small for the request.
+fn has_room(heap: *mut Heap, size: usize) -> bool {
+    unsafe {
+        if (*heap).free_size < size + Block::size() {
+            return false;
+        }
+        if (*heap).block_count == 0 || get_free_block(size, heap).is_some() {
+            return true;
+        }
+        let last = get_last_block(heap);
+        let end = block_shift!(last).add((*last).data_size()) as usize;
+        heap as usize + (*heap).total_size - end >= size + Block::size()
+    }
+}
+
+/// Takes the smallest cached mapping that fits `size` without wasting more
+/// than half of it.
+fn take_cached_large(handle: &mut HeapHandle, size: usize) -> Option<*mut Block> {
+    let mut best: Option<*mut Block> = None;
+    let mut curr = handle.large_cache;
+    while !curr.is_null() {
+        let capacity = unsafe { (*curr).data_size() };
+        if capacity >= size
+            && capacity / 2 <= size
+            && best.is_none_or(|best| capacity < unsafe { (*best).data_size() })
+        {
+            best = Some(curr);
+        }
+        curr = unsafe { (*curr).next as *mut Block };
+    }
+    let best = best?;
+    uncache_large(handle, best);
+    Some(best)
+}
+
+fn uncache_large(handle: &mut HeapHandle, block: *mut Block) {
+    unsafe {
+        let next = (*block).next as *mut Block;
+        let previous = (*block).previous as *mut Block;
+        if previous.is_null() {
+            handle.large_cache = next;
+        } else {
+            (*previous).next = next;
+        }
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+    }
+    handle.large_cache_len -= 1;
+    handle.large_cache_bytes -= large_mapping(block).1;
+}
+
+/// Keeps a freed large mapping for reuse, unmapping the least recently
+/// freed ones once the cache is over its entry or byte limit.
+fn cache_large(handle: &mut HeapHandle, block: *mut Block) -> Result<(), FreeError> {
+    unsafe { (*block).set_free(true) };
+    let block = coalesce_large(handle, block);
+    unsafe {
+        (*block).previous = null();
+        (*block).next = handle.large_cache;
+        if !handle.large_cache.is_null() {
+            (*handle.large_cache).previous = block;
+        }
+        handle.large_cache = block;
+        handle.large_cache_len += 1;
+        handle.large_cache_bytes += large_mapping(block).1;
+    }
+    let cap = LARGE_CACHE_CAP.load(Ordering::Relaxed);
+    while handle.large_cache_len > LARGE_CACHE_ENTRIES || handle.large_cache_bytes > cap {
+        let mut oldest = handle.large_cache;
+        unsafe {
+            while !(*oldest).next.is_null() {
+                oldest = (*oldest).next as *mut Block;
+            }
+        }
+        uncache_large(handle, oldest);
+        let (start, length) = large_mapping(oldest);
+        mem_unmap(start, length).map_err(|_| FreeError::Unmapped)?;
+    }
+    Ok(())
+}
+
+/// Merges a freed large block with the cached mappings right next to it,
+/// taking them out of the cache, and returns the block heading the merged
+/// region. Mappings freed one by one can then serve a request larger than
+/// any of them. Nothing merges on Windows, which only releases a
+/// reservation as a whole.
+fn coalesce_large(handle: &mut HeapHandle, mut block: *mut Block) -> *mut Block {
+    if cfg!(windows) {
+        return block;
+    }
+    let mut curr = handle.large_cache;
+    while !curr.is_null() {
+        let next = unsafe { (*curr).next as *mut Block };
+        let (start, length) = large_mapping(block);
+        let (curr_start, curr_length) = large_mapping(curr);
+        let (lower, end) = if start as usize + length == curr_start as usize {
+            (block, curr_start as usize + curr_length)
+        } else if curr_start as usize + curr_length == start as usize {
+            (curr, start as usize + length)
+        } else {
+            curr = next;
+            continue;
+        };
+        uncache_large(handle, curr);
+        unsafe { (*lower).set_data_size(end - block_shift!(lower) as usize) };
+        block = lower;
+        // The region grew, so cached mappings already passed may border it.
+        curr = handle.large_cache;
+    }
+    block
+}
+
+/// The mapping behind a large block: its start and length. The OS maps
+/// whole pages, so the mapping runs on past the block's data to the end of
+/// its last page. A guarded block's mapping runs from the page holding its
+/// header through the guard page.
+fn large_mapping(block: *const Block) -> (*const c_void, usize) {
+    let page = get_page_size();
+    let end = block as usize + Block::size() + unsafe { (*block).data_size() };
+    if unsafe { (*block).is_guarded() } {
+        let start = block as usize & !(page - 1);
+        return (start as *const c_void, end - start + page);
+    }
+    let start = block as usize - block_lead(block);
+    (start as *const c_void, (end - start).next_multiple_of(page))
+}
+
+/// Maps a large block whose data ends right where an inaccessible page
+/// begins, so overflowing writes fault instead of landing in other memory.
+fn map_guarded(size: usize) -> Option<*mut Block> {
+    let page = get_page_size();
+    let length = align(page, size.checked_add(Block::size())?)?;
+    let start = mem_map(length.checked_add(page)?, MMAP_PROT_READ_WRITE, large_flags()).ok()?;
+    let guard = unsafe { start.add(length) };
+    if mem_protect(guard, page, MMAP_PROT_NONE).is_err() {
+        let _ = mem_unmap(start, length + page);
+        return None;
+    }
+    Some(unsafe { guard.sub(size + Block::size()) as *mut Block })
+}
+
+/// Maps a large block on huge pages, rounding it up to whole huge pages. Falls
+/// back to a plain mapping when the system has no huge pages to give.
+fn map_huge(size: usize) -> Option<(*mut Block, usize)> {
+    let length = align(HUGE_PAGE_SIZE, size.checked_add(Block::size())?)?;
+    match mem_map(length, MMAP_PROT_READ_WRITE, MMAP_HUGE_PAGES | large_flags()) {
+        Ok(block) => Some((block as *mut Block, length - Block::size())),
+        Err(_) => Some((map_plain(size)?, size)),
+    }
+}
+
+fn map_plain(size: usize) -> Option<*mut Block> {
+    let length = size.checked_add(Block::size())?;
+    Some(mem_map(length, MMAP_PROT_READ_WRITE, large_flags()).ok()? as *mut Block)
+}
+
+/// The `mem_map` flags for a large block, as chosen through [`set_prefault`].
+fn large_flags() -> i32 {
+    match PREFAULT.load(Ordering::Relaxed) {
+        true => MMAP_POPULATE,
+        false => MMAP_NO_FLAGS,
+    }
+}
+
+/// Maps a large block whose data is `alignment`-aligned, returning it with
+/// its capacity and the lead in front of its header. The capacity takes up
+/// whatever the alignment left over at the end of the mapping.
+fn map_aligned(size: usize, alignment: usize) -> Option<(*mut Block, usize, usize)> {
+    // Mappings are page-aligned, so up to a page the lead is known up front.
+    let slack = if alignment <= PAGE_SIZE {
+        align(alignment, Block::size())?
+    } else {
+        alignment.checked_add(Block::size())?
+    };
+    let length = size.checked_add(slack)?;
+    let start = mem_map(length, MMAP_PROT_READ_WRITE, large_flags()).ok()? as usize;
+    let block = align(alignment, start + Block::size())? - Block::size();
+    let lead = block - start;
+    Some((block as *mut Block, length - lead - Block::size(), lead))
+}
+
+/// How a standalone large allocation is mapped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mapping {
+    Plain,
+    Guarded,
+    HugePages,
+}
+
+impl Mapping {
+    /// The kind chosen through [`set_guard_pages`] and [`set_huge_pages`].
+    fn current() -> Self {
+        if GUARD_PAGES.load(Ordering::Relaxed) {
+            Mapping::Guarded
+        } else if HUGE_PAGES.load(Ordering::Relaxed) {
+            Mapping::HugePages
+        } else {
+            Mapping::Plain
+        }
+    }
+}
+
+fn alloc_large(handle: &mut HeapHandle, size: usize, mapping: Mapping) -> *mut u8 {
+    // A guard page cannot be added to a cached mapping, so guarded blocks
+    // leave the cache alone.
+    let cached = match mapping {
+        Mapping::Guarded => None,
+        _ => take_cached_large(handle, size),
+    };
+    let mapped = match (mapping, cached) {
+        (Mapping::Guarded, _) => {
+            map_or_trim(handle, || map_guarded(size)).map(|block| (block, size, 0))
+        }
+        (_, Some(block)) => Some((block, unsafe { (*block).data_size() }, block_lead(block))),
+        (Mapping::HugePages, None) => {
+            map_or_trim(handle, || map_huge(size)).map(|(block, capacity)| (block, capacity, 0))
+        }
+        (Mapping::Plain, None) => {
+            map_or_trim(handle, || map_plain(size)).map(|block| (block, size, 0))
+        }
+    };
+    let Some((block, capacity, lead)) = mapped else {
+        return ptr::null_mut();
+    };
+    handle.fresh = mapping == Mapping::Guarded || cached.is_none();
+    track_large(handle, block, capacity, lead, mapping == Mapping::Guarded)
+}
+
+/// Allocates a large block whose data is `alignment`-aligned. These always
+/// get a plain mapping of their own.
+fn alloc_large_aligned(handle: &mut HeapHandle, size: usize, alignment: usize) -> *mut u8 {
+    match map_or_trim(handle, || map_aligned(size, alignment)) {
+        Some((block, capacity, lead)) => track_large(handle, block, capacity, lead, false),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Runs `map`, and if it fails while [`set_trim_on_oom`] is on, unmaps what
+/// `handle` keeps around for reuse and runs it once more. There is no second
+/// try when nothing could be released.
+fn map_or_trim<T>(handle: &mut HeapHandle, mut map: impl FnMut() -> Option<T>) -> Option<T> {
+    map().or_else(|| match TRIM_ON_OOM.load(Ordering::Relaxed) && heap_trim(handle) > 0 {
+        true => map(),
+        false => None,
+    })
+}
+
+/// Writes the header of a freshly mapped or reused large block and links it
+/// into the large list.
+fn track_large(
+    handle: &mut HeapHandle,
+    block: *mut Block,
+    capacity: usize,
+    lead: usize,
+    guarded: bool,
+) -> *mut u8 {
+    let mut header = Block::new(capacity);
+    header.set_guarded(guarded);
+    header.set_flag(LEAD, lead != 0);
+    header.next = handle.large;
+    unsafe {
+        // Headers are 16-byte aligned in page-aligned mappings, so a lead
+        // always has room for the word.
+        if lead != 0 {
+            block.cast::<usize>().sub(1).write(lead);
+        }
+        block.write(header);
+        if !handle.large.is_null() {
+            (*handle.large).previous = block;
+        }
+        handle.large = block;
+        block_shift!(block)
+    }
+}
+
+fn large_block(ptr: *const c_void, head: *mut Block) -> Option<*mut Block> {
+    let mut curr = head;
+    while !curr.is_null() {
+        if ptr::eq(unsafe { block_shift!(curr) }, ptr.cast()) {
+            return Some(curr);
+        }
+        curr = unsafe { (*curr).next as *mut Block };
+    }
+    None
+}
+
+fn free_large(handle: &mut HeapHandle, block: *mut Block) -> Result<(), FreeError> {
+    unsafe {
+        let next = (*block).next as *mut Block;
+        let previous = (*block).previous as *mut Block;
+        if previous.is_null() {
+            handle.large = next;
+        } else {
+            (*previous).next = next;
+        }
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+        if (*block).is_guarded() {
+            let (start, length) = large_mapping(block);
+            return mem_unmap(start, length).map_err(|_| FreeError::Unmapped);
+        }
+    }
+    cache_large(handle, block)
+}
+
+/// The canary words at the end of a block's data.
+fn redzone(block: *const Block) -> *mut u32 {
+    unsafe { block_shift!(block).add((*block).data_size() - REDZONE).cast::<u32>() }
+}
+
+fn arm_redzone(block: *const Block) {
+    let canary = redzone(block);
+    for i in 0..REDZONE / mem::size_of::<u32>() {
+        unsafe { canary.add(i).write(CANARY) };
+    }
+}
+
+fn redzone_intact(block: *const Block) -> bool {
+    let canary = redzone(block);
+    (0..REDZONE / mem::size_of::<u32>()).all(|i| unsafe { canary.add(i).read() } == CANARY)
+}
+
+/// Writes the canary of the block behind `ptr` and hands `ptr` back.
+fn armed(ptr: *mut u8) -> *mut u8 {
+    arm_redzone(unsafe { block_unshift!(ptr) });
+    ptr
+}
+
+/// Fills the data of `block` with `byte` when the `poison` feature is on.
+fn poison(block: *const Block, byte: u8) {
+    if cfg!(feature = "poison") {
+        unsafe { block_shift!(block).write_bytes(byte, (*block).data_size()) };
+    }
+}
+
+fn heap_alloc(handle: &mut HeapHandle, size: usize) -> *mut c_void {
+    // Zero-size requests still get a block of their own, so every pointer
+    // handed out is unique and can be freed.
+    let size = rounded_size(size.max(1), &handle.config);
+    let Some(size) = size.checked_add(REDZONE) else {
+        return ptr::null_mut();
+    };
+    let ptr = alloc_block(handle, size);
+    hand_out(handle, ptr)
+}
+
+/// Hands out `count` blocks of `size` bytes into `out`, carved back to back
+/// out of one new heap sized to hold them all. Sizes that get a mapping of
+/// their own are allocated one by one instead. Leaves `out` empty, having
+/// kept nothing, when the OS has no memory left.
+#[cfg(feature = "std")]
+fn heap_alloc_batch(
+    handle: &mut HeapHandle,
+    size: usize,
+    count: usize,
+    out: &mut Vec<*mut c_void>,
+) {
+    let Some(needed) = size.max(1).checked_add(REDZONE).and_then(|size| align(MIN_ALIGN, size))
+    else {
+        return;
+    };
+    if count == 0 {
+        return;
+    }
+    if needed > handle.config.large_threshold {
+        for _ in 0..count {
+            let ptr = heap_alloc(handle, size);
+            if ptr.is_null() {
+                for ptr in out.drain(..) {
+                    let _ = heap_free(handle, ptr);
+                }
+                return;
+            }
+            out.push(ptr);
+        }
+        return;
+    }
+    let config = handle.config;
+    let Some(heap) = map_or_trim(handle, || create_batch_heap(needed, count, &config)) else {
+        return;
+    };
+    link_heap(handle, heap);
+    let mut previous: *mut Block = ptr::null_mut();
+    let mut block = unsafe { heap_shift!(heap).cast::<Block>() };
+    for _ in 0..count {
+        let mut header = Block::new(needed);
+        header.previous = previous;
+        unsafe {
+            block.write(header);
+            if !previous.is_null() {
+                (*previous).next = block;
+            }
+            (*heap).free_size -= needed + Block::size();
+            (*heap).block_count += 1;
+            (*heap).last = block;
+            out.push(hand_out(handle, block_shift!(block)));
+            previous = block;
+            block = block_shift!(block).add(needed).cast::<Block>();
+        }
+    }
+    // The heap was linked while still empty.
+    handle.idle_bytes -= unsafe { (*heap).total_size };
+}
+
+/// Rounds a request up to the next power of two while that stays within the
+/// cap set by [`AllocatorConfig::round_to_powers_of_two`], so that requests
+/// of similar sizes fit each other's freed blocks.
+fn rounded_size(size: usize, config: &AllocatorConfig) -> usize {
+    match size.checked_next_power_of_two() {
+        Some(rounded) if rounded <= config.rounding_cap => rounded,
+        _ => size,
+    }
+}
+
+/// Allocates `size` zeroed bytes. New large mappings come zeroed from the OS
+/// and are left alone; everything else may be reused and is cleared.
+fn heap_calloc(handle: &mut HeapHandle, size: usize) -> *mut c_void {
+    handle.fresh = false;
+    let ptr = heap_alloc(handle, size);
+    // Poisoning has filled even a new mapping.
+    let zeroed = handle.fresh && !cfg!(feature = "poison");
+    if !ptr.is_null() && !zeroed {
+        unsafe { ptr.cast::<u8>().write_bytes(0, size) };
+    }
+    ptr
+}
+
+/// Accounts for a live block going from `before` to `after` bytes, zero
+/// standing for no block at all: raises the peak to match and moves the
+/// block to the size class it now falls in.
+fn track_live(handle: &mut HeapHandle, before: usize, after: usize) {
+    handle.live_bytes = handle.live_bytes - before + after;
+    handle.peak_bytes = handle.peak_bytes.max(handle.live_bytes);
+    if before > 0 {
+        handle.class_counts[bin_index(before - REDZONE)] -= 1;
+    }
+    if after > 0 {
+        handle.class_counts[bin_index(after - REDZONE)] += 1;
+    }
+}
+
+/// Pairs each size class's smallest usable size with its count.
+fn class_histogram(counts: &[usize; BIN_COUNT]) -> [(usize, usize); BIN_COUNT] {
+    let mut histogram = [(0, 0); BIN_COUNT];
+    for (class, (entry, &count)) in histogram.iter_mut().zip(counts).enumerate() {
+        *entry = (MIN_ALIGN << class, count);
+    }
+    histogram
+}
+
+/// Prepares a freshly allocated block for its caller: poisons it, records
+/// its tag and call site and arms its redzone.
+#[cfg_attr(not(feature = "leak-check"), allow(unused_variables))]
+fn hand_out(handle: &mut HeapHandle, ptr: *mut u8) -> *mut c_void {
+    if ptr.is_null() {
+        return ptr::null_mut();
+    }
+    track_live(handle, 0, unsafe { (*block_unshift!(ptr)).data_size() });
+    poison(unsafe { block_unshift!(ptr) }, FRESH_BYTE);
+    unsafe { (*(block_unshift!(ptr))).set_stamps(handle.generation, handle.tag) };
+    #[cfg(feature = "leak-check")]
+    unsafe {
+        (*(block_unshift!(ptr))).site = handle.site;
+    }
+    armed(ptr).cast()
+}
+
+fn alloc_block(handle: &mut HeapHandle, size: usize) -> *mut u8 {
+    let Some(size) = align(MIN_ALIGN, size) else {
+        return ptr::null_mut();
+    };
+    if size > handle.config.large_threshold {
+        return alloc_large(handle, size, Mapping::current());
+    }
+
+    let suitable_heap = match get_heap(size, handle.heap, &handle.config) {
+        Some(h) => h,
+        None => {
+            let config = handle.config;
+            let Some(new_heap) = map_or_trim(handle, || create_heap(size, &config)) else {
+                return ptr::null_mut();
+            };
+            link_heap(handle, new_heap);
+            new_heap
+        }
+    };
+    let idle = heap_is_empty(suitable_heap);
+    let mut block_header = Block::new(size);
+    let ptr = if unsafe { suitable_heap.read().block_count } == 0 {
+        let last_block = unsafe{ heap_shift!(suitable_heap).cast::<Block>() };
+        unsafe {
+            // An exact-size heap holds a single block, so it gets the rest of
+            // the last page as well instead of a tail other sizes would carve
+            // up.
+            if matches!((*suitable_heap).group, HeapGroup::Large(_)) {
+                block_header.set_data_size((*suitable_heap).free_size - Block::size());
+            }
+            if !(*suitable_heap).take(block_header.data_size() + Block::size()) {
+                return ptr::null_mut();
+            }
+            (*suitable_heap).block_count += 1;
+            (*suitable_heap).last = last_block;
+            last_block.write(block_header);
+        }
+        unsafe { block_shift!(last_block) }
+    } else if let Some(free_block) = get_free_block(size, suitable_heap) {
+        unsafe {
+            if !(*suitable_heap).take((*free_block).data_size() + Block::size()) {
+                return ptr::null_mut();
+            }
+            unlink_free_block(suitable_heap, free_block);
+            (*free_block).set_free(false);
+            split_block(free_block, suitable_heap, size, &handle.config);
+            block_shift!(free_block)
+        }
+    } else {
+        let last_block = get_last_block(suitable_heap);
+        unsafe {
+            if !(*suitable_heap).take(block_header.data_size() + Block::size()) {
+                return ptr::null_mut();
+            }
+            let new_block =
+                block_shift!(last_block).add(last_block.read().data_size()).cast::<Block>();
+            block_header.previous = last_block;
+            (*last_block).next = new_block as *const Block;
+            (*suitable_heap).block_count += 1;
+            (*suitable_heap).last = new_block;
+            new_block.write(block_header);
+            block_shift!(new_block)
+        }
+    };
+    if idle {
+        handle.idle_bytes -= unsafe { (*suitable_heap).total_size };
+    }
+    ptr
+}
+
+fn heap_stats(handle: &HeapHandle, stats: &mut HeapStats) {
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            let group = match (*heap).group {
+                HeapGroup::Tiny(_) => &mut stats.tiny,
+                HeapGroup::Small(_) => &mut stats.small,
+                HeapGroup::Large(_) => &mut stats.large,
+            };
+            group.mappings += 1;
+            group.mapped_bytes += (*heap).total_size;
+            group.free_bytes += (*heap).free_size;
+            let mut block = heap_shift!(heap).cast::<Block>().cast_const();
+            while (*heap).block_count > 0 && !block.is_null() {
+                if !(*block).is_free() {
+                    group.live_blocks += 1;
+                }
+                block = (*block).next;
+            }
+            heap = (*heap).next;
+        }
+    }
+    let mut block = handle.large;
+    while !block.is_null() {
+        unsafe {
+            stats.large.mappings += 1;
+            stats.large.mapped_bytes += large_mapping(block).1;
+            stats.large.live_blocks += 1;
+            block = (*block).next as *mut Block;
+        }
+    }
+    stats.large.mappings += handle.large_cache_len;
+    stats.large.mapped_bytes += handle.large_cache_bytes;
+    stats.large.free_bytes += handle.large_cache_bytes;
+}
+
+fn heap_blocks(handle: &HeapHandle, f: &mut impl FnMut(BlockInfo)) {
+    let info = |block: *const Block| unsafe {
+        BlockInfo {
+            ptr: block_shift!(block).cast(),
+            size: (*block).data_size() - REDZONE,
+            free: (*block).is_free(),
+        }
+    };
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            let mut block = heap_shift!(heap).cast::<Block>().cast_const();
+            while (*heap).block_count > 0 && !block.is_null() {
+                f(info(block));
+                block = (*block).next;
+            }
+            heap = (*heap).next;
+        }
+    }
+    for mut block in [handle.large, handle.large_cache] {
+        while !block.is_null() {
+            f(info(block));
+            block = unsafe { (*block).next as *mut Block };
+        }
+    }
+}
+
+/// Copies every live allocation of `handle` into `into`, in the order the
+/// blocks are laid out, keeping their tags. Returns the old and new address
+/// of each, or `None` when `into` runs out of memory; whatever was copied by
+/// then stays in `into`.
+#[cfg(feature = "std")]
+fn heap_snapshot(
+    handle: &HeapHandle,
+    into: &mut HeapHandle,
+) -> Option<Vec<(*mut c_void, *mut c_void)>> {
+    let mut live = Vec::new();
+    heap_blocks(handle, &mut |info| {
+        let block = unsafe { block_unshift!(info.ptr) };
+        if !info.free && !in_quarantine(handle, block) {
+            live.push((info.ptr.cast_mut(), info.size));
+        }
+    });
+    live.into_iter()
+        .map(|(ptr, size)| unsafe {
+            let block = block_unshift!(ptr);
+            into.tag = (*block).tag();
+            #[cfg(feature = "leak-check")]
+            {
+                into.site = (*block).site;
+            }
+            let copy = heap_alloc(into, size);
+            into.tag = 0;
+            if copy.is_null() {
+                return None;
+            }
+            ptr::copy_nonoverlapping(ptr.cast::<u8>(), copy.cast::<u8>(), size);
+            Some((ptr, copy))
+        })
+        .collect()
+}
+
+/// Writes one line per heap and per block of `handle`, blocks indented under
+/// their heap, and one line per large mapping.
+fn heap_dump(handle: &HeapHandle, out: &mut impl fmt::Write) -> fmt::Result {
+    let state = |free: bool| if free { "free" } else { "used" };
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            let group = match (*heap).group {
+                HeapGroup::Tiny(_) => "tiny",
+                HeapGroup::Small(_) => "small",
+                HeapGroup::Large(_) => "large",
+            };
+            writeln!(
+                out,
+                "{group} heap {heap:p}: {} bytes, {} free, {} blocks",
+                (*heap).total_size,
+                (*heap).free_size,
+                (*heap).block_count
+            )?;
+            let mut block = heap_shift!(heap).cast::<Block>().cast_const();
+            while (*heap).block_count > 0 && !block.is_null() {
+                let size = (*block).data_size() - REDZONE;
+                let ptr = block_shift!(block);
+                writeln!(out, "  {ptr:p}: {size} bytes, {}", state((*block).is_free()))?;
+                block = (*block).next;
+            }
+            heap = (*heap).next;
+        }
+    }
+    for mut block in [handle.large, handle.large_cache] {
+        while !block.is_null() {
+            unsafe {
+                let size = (*block).data_size() - REDZONE;
+                let ptr = block_shift!(block);
+                let state = state((*block).is_free());
+                writeln!(out, "large mapping {ptr:p}: {size} bytes, {state}")?;
+                block = (*block).next as *mut Block;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs [`heap_verify`] on `handle` when debug assertions are on, so that
+/// development builds check the heaps after every `malloc` and `free`. The
+/// caller already holds the arena, and panics only once it is unlocked.
+fn debug_verify(handle: &HeapHandle) -> Result<(), Corruption> {
+    match cfg!(debug_assertions) {
+        true => heap_verify(handle),
+        false => Ok(()),
+    }
+}
+
+/// A broken invariant of the heap structures, found by [`heap_verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Corruption {
+    /// A block does not lie between the heap's header and its end.
+    OutOfRange(*const Heap, *const Block),
+    /// A block's links disagree with its neighbours or its place in the
+    /// heap.
+    BrokenLink(*const Heap, *const Block),
+    /// The heap records a different number of blocks than its chain holds.
+    BlockCount(*const Heap, usize, usize),
+    /// The heap records different free bytes than its blocks and tail add
+    /// up to.
+    FreeSize(*const Heap, usize, usize),
+    /// A free list holds a block that is not one of the heap's free blocks
+    /// of that size class.
+    FreeList(*const Heap, *const Block),
+    /// A free block of the heap is missing from the free lists.
+    Unlisted(*const Heap),
+    /// A large mapping's links disagree with its neighbours.
+    LargeLink(*const Block),
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Corruption::OutOfRange(heap, block) => {
+                write!(f, "heap {heap:p}: block {block:p} lies outside the heap")
+            }
+            Corruption::BrokenLink(heap, block) => {
+                write!(f, "heap {heap:p}: block {block:p} is linked inconsistently")
+            }
+            Corruption::BlockCount(heap, recorded, walked) => {
+                write!(f, "heap {heap:p}: records {recorded} blocks but links {walked}")
+            }
+            Corruption::FreeSize(heap, recorded, walked) => {
+                write!(f, "heap {heap:p}: records {recorded} free bytes but has {walked}")
+            }
+            Corruption::FreeList(heap, block) => {
+                write!(f, "heap {heap:p}: block {block:p} is misfiled in the free lists")
+            }
+            Corruption::Unlisted(heap) => {
+                write!(f, "heap {heap:p}: a free block is missing from the free lists")
+            }
+            Corruption::LargeLink(block) => {
+                write!(f, "large mapping {block:p} is linked inconsistently")
+            }
+        }
+    }
+}
+
+/// Checks every heap and large mapping of `handle` against the invariants
+/// the rest of the allocator relies on, stopping at the first one broken.
+/// Walks are bounded by the recorded block counts, so corrupted links cannot
+/// send it around in circles.
+fn heap_verify(handle: &HeapHandle) -> Result<(), Corruption> {
+    let mut heap = handle.heap as *const Heap;
+    let mut previous_heap = ptr::null();
+    while !heap.is_null() {
+        unsafe {
+            if !ptr::eq((*heap).previous, previous_heap) {
+                return Err(Corruption::BrokenLink(heap, ptr::null()));
+            }
+            verify_blocks(heap)?;
+            previous_heap = heap;
+            heap = (*heap).next;
+        }
+    }
+    for list in [handle.large, handle.large_cache] {
+        let mut block = list as *const Block;
+        let mut previous = ptr::null();
+        while !block.is_null() {
+            unsafe {
+                if (*block).previous != previous {
+                    return Err(Corruption::LargeLink(block));
+                }
+                previous = block;
+                block = (*block).next;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the block chain, free space and free lists of one heap.
+fn verify_blocks(heap: *const Heap) -> Result<(), Corruption> {
+    unsafe {
+        let start = heap as usize + Heap::size();
+        let end = heap as usize + (*heap).total_size;
+        let recorded = (*heap).block_count;
+        let (mut walked, mut free_blocks, mut free_bytes) = (0, 0, 0);
+        // Blocks follow one another without gaps, from right past the header.
+        let mut expected = start;
+        let mut previous = ptr::null();
+        let mut block = match recorded > 0 {
+            true => heap_shift!(heap).cast::<Block>().cast_const(),
+            false => ptr::null(),
+        };
+        while !block.is_null() {
+            if walked == recorded {
+                return Err(Corruption::BlockCount(heap, recorded, walked + 1));
+            }
+            if block as usize != expected || (*block).previous != previous {
+                return Err(Corruption::BrokenLink(heap, block));
+            }
+            let block_end = (*block).data_size().checked_add(block as usize + Block::size());
+            if block_end.is_none_or(|block_end| block_end > end) {
+                return Err(Corruption::OutOfRange(heap, block));
+            }
+            if (*block).is_free() {
+                free_blocks += 1;
+                free_bytes += Block::size() + (*block).data_size();
+            }
+            walked += 1;
+            expected = block as usize + Block::size() + (*block).data_size();
+            previous = block;
+            block = (*block).next;
+        }
+        if walked != recorded {
+            return Err(Corruption::BlockCount(heap, recorded, walked));
+        }
+        // Whatever follows the last block is the unused tail.
+        free_bytes += end - expected;
+        if free_bytes != (*heap).free_size {
+            return Err(Corruption::FreeSize(heap, (*heap).free_size, free_bytes));
+        }
+        let mut binned = 0;
+        for (bin, &first) in (*heap).bins.iter().enumerate() {
+            let mut block = first as *const Block;
+            while !block.is_null() {
+                let in_heap = (start..end).contains(&(block as usize));
+                if binned == free_blocks
+                    || !in_heap
+                    || !(*block).is_free()
+                    || bin_index((*block).data_size()) != bin
+                {
+                    return Err(Corruption::FreeList(heap, block));
+                }
+                binned += 1;
+                block = (*block).free_next();
+            }
+        }
+        if binned != free_blocks {
+            return Err(Corruption::Unlisted(heap));
+        }
+    }
+    Ok(())
+}
+
+fn parent_heap(block: *const c_void, head: *mut Heap) -> Option<*mut Heap> {
+    let mut curr_heap = head;
+    while !curr_heap.is_null() {
+        if blocks_of(curr_heap).any(|curr| ptr::eq(unsafe { block_shift!(curr) }, block.cast())) {
+            return Some(curr_heap);
+        }
+        curr_heap = unsafe { (*curr_heap).next }
+    }
+    None
+}
+
+/// Folds every free block following `block` into it.
+///
+/// `free_size` counts the header of a free block along with its data, so the
+/// headers reclaimed here are already credited and need no adjusting.
+fn merge_right(block: *mut Block, heap: *mut Heap) {
+    unsafe {
+        while !(*block).next.is_null() && (*(*block).next).is_free() {
+            let absorbed = (*block).next as *mut Block;
+            unlink_free_block(heap, absorbed);
+            (*block).set_data_size((*block).data_size() + (*absorbed).data_size() + Block::size());
+            let nxt = (*(*block).next).next as *mut Block;
+            (*block).next = nxt;
+            if nxt.is_null() {
+                (*heap).last = block;
+            } else {
+                (*nxt).previous = block;
+            }
+            (*heap).block_count -= 1;
+        }
+    }
+}
+
+/// Whether none of the blocks of `heap` is in use. Free blocks never sit
+/// next to each other, so that leaves one free block at most.
+fn heap_is_empty(heap: *const Heap) -> bool {
+    unsafe {
+        let first = heap_shift!(heap).cast::<Block>();
+        (*heap).block_count == 0 || ((*heap).block_count == 1 && (*first).is_free())
+    }
+}
+
+/// Takes `heap` out of the heap list of `handle`, joining its neighbours, or
+/// making its successor the head. The heap stays mapped.
+fn unlink_heap(heap: *mut Heap, handle: &mut HeapHandle) {
+    if heap_is_empty(heap) {
+        handle.idle_bytes -= unsafe { (*heap).total_size };
+    }
+    unsafe {
+        let (previous, next) = ((*heap).previous, (*heap).next);
+        if previous.is_null() {
+            handle.heap = next;
+        } else {
+            (*previous).next = next;
+        }
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+        (*heap).previous = ptr::null_mut();
+        (*heap).next = ptr::null_mut();
+    }
+}
+
+/// Folds `block` into every free block preceding it and files the result
+/// under its size class. Unmaps the heap if that leaves it empty.
+fn merge_left(
+    block: *mut Block,
+    heap_handle: &mut HeapHandle,
+    heap: *mut Heap,
+) -> Result<(), FreeError> {
+    unsafe {
+        let mut block = block;
+        while !(*block).previous.is_null() && (*(*block).previous).is_free() {
+            let prev_ptr = (*block).previous as *mut Block;
+            let next_ptr = (*block).next as *mut Block;
+            unlink_free_block(heap, prev_ptr);
+            (*prev_ptr).next = next_ptr;
+            let merged = (*prev_ptr).data_size() + (*block).data_size() + Block::size();
+            (*prev_ptr).set_data_size(merged);
+
+            if next_ptr.is_null() {
+                (*heap).last = prev_ptr;
+            } else {
+                (*next_ptr).previous = prev_ptr;
+            }
+
+            (*heap).block_count -= 1;
+            block = prev_ptr;
+        }
+        push_free_block(heap, block);
+        if !heap_is_empty(heap) {
+            return Ok(());
+        }
+        heap_handle.idle_bytes += (*heap).total_size;
+        // A lone heap stays mapped so the next allocation can reuse it, and
+        // so do empty heaps within the trim threshold; `trim` releases them.
+        let lone = (*heap).previous.is_null() && (*heap).next.is_null();
+        if !lone && heap_handle.idle_bytes > heap_handle.config.trim_threshold {
+            let size = (*heap).total_size;
+            unlink_heap(heap, heap_handle);
+            mem_unmap(heap as *const c_void, size).map_err(|_| FreeError::Unmapped)?;
+        }
+    }
+    Ok(())
+}
+
+fn heap_free(handle: &mut HeapHandle, ptr: *const c_void) -> Result<(), FreeError> {
+    if ptr.is_null() {
+        return Err(FreeError::InvalidPointer);
+    }
+    if let Some(block) = large_block(ptr, handle.large) {
+        if in_quarantine(handle, block) {
+            return Err(FreeError::DoubleFree);
+        }
+        if !redzone_intact(block) {
+            return Err(FreeError::Corrupted(ptr));
+        }
+        track_live(handle, unsafe { (*block).data_size() }, 0);
+        handle.generation = handle.generation.wrapping_add(1);
+        if handle.config.quarantine > 0 || handle.quarantine_len > 0 {
+            return quarantine_block(handle, block);
+        }
+        poison(block, FREED_BYTE);
+        return free_large(handle, block);
+    }
+    let Some(heap) = parent_heap(ptr, handle.heap) else {
+        return match aligned_base(handle, ptr) {
+            Some(base) => heap_free(handle, base),
+            None => Err(FreeError::InvalidPointer),
+        };
+    };
+    let block = unsafe{ block_unshift!(ptr) };
+    if unsafe { block.read().is_free() } || in_quarantine(handle, block) {
+        return Err(FreeError::DoubleFree);
+    }
+    if !redzone_intact(block) {
+        return Err(FreeError::Corrupted(ptr));
+    }
+    track_live(handle, unsafe { (*block).data_size() }, 0);
+    handle.generation = handle.generation.wrapping_add(1);
+    if handle.config.quarantine > 0 || handle.quarantine_len > 0 {
+        return quarantine_block(handle, block);
+    }
+    release_heap_block(handle, heap, block)
+}
+
+/// Hands a freed heap block back to `heap`, merging it with free neighbours.
+fn release_heap_block(
+    handle: &mut HeapHandle,
+    heap: *mut Heap,
+    block: *mut Block,
+) -> Result<(), FreeError> {
+    poison(block, FREED_BYTE);
+    unsafe {
+        (*block).set_free(true);
+        (*heap).free_size += (*block).data_size() + Block::size();
+    }
+    // Free blocks never sit next to each other, so merging once on each side
+    // is enough to fold a block freed between two free neighbours into one.
+    merge_right(block, heap);
+    merge_left(block, handle, heap)
+}
+
+/// Whether `block` was freed and is held back in the quarantine.
+fn in_quarantine(handle: &HeapHandle, block: *mut Block) -> bool {
+    (0..handle.quarantine_len)
+        .any(|i| handle.quarantine[(handle.quarantine_start + i) % QUARANTINE_SLOTS] == block)
+}
+
+/// Holds a freed block back from reuse, filled with the freed pattern, and
+/// releases the oldest ones once the quarantine is over its size. Returns
+/// [`FreeError::Corrupted`] for a released block whose pattern was written
+/// over while it sat there, as only a use after free can have done; the
+/// block is released all the same.
+fn quarantine_block(handle: &mut HeapHandle, block: *mut Block) -> Result<(), FreeError> {
+    unsafe { block_shift!(block).write_bytes(FREED_BYTE, (*block).data_size() - REDZONE) };
+    let end = (handle.quarantine_start + handle.quarantine_len) % QUARANTINE_SLOTS;
+    handle.quarantine[end] = block;
+    handle.quarantine_len += 1;
+    let mut result = Ok(());
+    while handle.quarantine_len > handle.config.quarantine {
+        let oldest = handle.quarantine[handle.quarantine_start];
+        handle.quarantine_start = (handle.quarantine_start + 1) % QUARANTINE_SLOTS;
+        handle.quarantine_len -= 1;
+        let data = unsafe { block_shift!(oldest) };
+        let size = unsafe { (*oldest).data_size() } - REDZONE;
+        if unsafe { slice::from_raw_parts(data, size) }.iter().any(|&b| b != FREED_BYTE) {
+            result = result.and(Err(FreeError::Corrupted(data.cast())));
+        }
+        let released = match large_block(data.cast(), handle.large) {
+            Some(_) => free_large(handle, oldest),
+            None => match parent_heap(data.cast(), handle.heap) {
+                Some(heap) => release_heap_block(handle, heap, oldest),
+                None => Err(FreeError::InvalidPointer),
+            },
+        };
+        result = result.and(released);
+    }
+    result
+}
+
+/// Allocates a block for `layout`. Alignments beyond `MIN_ALIGN` over-allocate
+/// so that a suitably aligned address lies inside the block, and record the
+/// distance back to the block start in the word just before the returned
+/// pointer.
+fn heap_allocate(handle: &mut HeapHandle, layout: Layout) -> *mut c_void {
+    let (size, alignment) = (layout.size(), layout.align());
+    if alignment <= MIN_ALIGN {
+        return heap_alloc(handle, size);
+    }
+    let Some(needed) = align(MIN_ALIGN, size.max(1)).and_then(|size| size.checked_add(REDZONE))
+    else {
+        return ptr::null_mut();
+    };
+    // Whole pages at up to page alignment, such as I/O buffers, get a
+    // mapping of their own too: the OS hands out page-aligned memory anyway.
+    let page = get_page_size();
+    let whole_pages = alignment <= page && size > 0 && size.is_multiple_of(page);
+    if needed > handle.config.large_threshold || whole_pages {
+        // Large blocks get their header placed so the data comes out aligned,
+        // instead of wasting `alignment` bytes of padding.
+        let ptr = alloc_large_aligned(handle, needed, alignment);
+        return hand_out(handle, ptr);
+    }
+    // A zero-sized request still needs a byte, or its aligned address could
+    // land on the end of the block, where `aligned_base` refuses it.
+    let Some(padded) = size.max(1).checked_add(alignment) else {
+        return ptr::null_mut();
+    };
+    let raw = heap_alloc(handle, padded);
+    if raw.is_null() {
+        return raw;
+    }
+    let data = align(alignment, raw as usize + mem::size_of::<usize>())
+        .expect("mapped addresses are far from usize::MAX");
+    let offset = data - raw as usize;
+    unsafe {
+        let ptr = raw.cast::<u8>().add(offset);
+        ptr.cast::<usize>().sub(1).write(offset);
+        ptr.cast()
+    }
+}
+
+/// Whether `ptr` falls inside one of the heaps or large mappings of `handle`,
+/// past the header of its first block.
+fn in_mapped_range(handle: &HeapHandle, ptr: *const c_void) -> bool {
+    let addr = ptr as usize;
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        let start = heap as usize;
+        let first = start + Heap::size() + Block::size();
+        if addr >= first && addr < start + unsafe { (*heap).total_size } {
+            return true;
+        }
+        heap = unsafe { (*heap).next };
+    }
+    let mut block = handle.large;
+    while !block.is_null() {
+        let data = unsafe { block_shift!(block) } as usize;
+        if addr >= data && addr <= data + unsafe { (*block).data_size() } {
+            return true;
+        }
+        block = unsafe { (*block).next as *mut Block };
+    }
+    false
+}
+
+/// Recovers the block start behind a pointer handed out by
+/// `heap_allocate` for an over-aligned layout.
+fn aligned_base(handle: &HeapHandle, ptr: *const c_void) -> Option<*mut c_void> {
+    // Only read the back-offset from memory this allocator still has mapped.
+    let word = ptr.cast::<u8>().wrapping_sub(mem::size_of::<usize>()).cast();
+    if !in_mapped_range(handle, ptr) || !in_mapped_range(handle, word) {
+        return None;
+    }
+    let offset = unsafe { ptr.cast::<usize>().sub(1).read_unaligned() };
+    if offset < mem::size_of::<usize>() || offset > ptr as usize {
+        return None;
+    }
+    let base = ptr.cast::<u8>().wrapping_sub(offset).cast::<c_void>().cast_mut();
+    // `heap_allocate` hands out the first address past the offset word
+    // with the requested alignment; anything else points into a block.
+    let alignment = 1 << (ptr as usize).trailing_zeros();
+    let first = align(alignment, base as usize + mem::size_of::<usize>())?;
+    if alignment <= MIN_ALIGN || first != ptr as usize {
+        return None;
+    }
+    if large_block(base, handle.large).is_none() && parent_heap(base, handle.heap).is_none() {
+        return None;
+    }
+    let block = unsafe { block_unshift!(base) };
+    (offset < unsafe { (*block).data_size() }).then_some(base)
+}
+
+/// Whether `ptr` was handed out by `handle` and not unmapped since.
+fn owns(handle: &HeapHandle, ptr: *const c_void) -> bool {
+    !ptr.is_null()
+        && (large_block(ptr, handle.large).is_some()
+            || parent_heap(ptr, handle.heap).is_some()
+            || aligned_base(handle, ptr).is_some())
+}
+
+/// The start of the data of the live block `ptr` points into, anywhere
+/// before its redzone, or `None` if it points into no live block of
+/// `handle`.
+fn heap_allocation_base(handle: &HeapHandle, ptr: *const c_void) -> Option<*mut c_void> {
+    let addr = ptr as usize;
+    let within = |block: *const Block| {
+        let data = unsafe { block_shift!(block) } as usize;
+        (data..data + unsafe { (*block).data_size() } - REDZONE).contains(&addr)
+    };
+    let live = |block: *mut Block| unsafe { !(*block).is_free() } && !in_quarantine(handle, block);
+    let mut block = handle.large;
+    while !block.is_null() {
+        if within(block) {
+            return live(block).then(|| unsafe { block_shift!(block) }.cast());
+        }
+        block = unsafe { (*block).next as *mut Block };
+    }
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        let start = heap as usize;
+        if addr > start && addr < start + unsafe { (*heap).total_size } {
+            let mut block = unsafe { heap_shift!(heap).cast::<Block>() };
+            while unsafe { (*heap).block_count } > 0 && !block.is_null() {
+                if within(block) {
+                    return live(block).then(|| unsafe { block_shift!(block) }.cast());
+                }
+                block = unsafe { (*block).next as *mut Block };
+            }
+            return None;
+        }
+        heap = unsafe { (*heap).next };
+    }
+    None
+}
+
+/// The live block behind `ptr` and the offset of `ptr` into its data, or
+/// `None` if it was not handed out by `handle`.
+fn live_block(handle: &HeapHandle, ptr: *const c_void) -> Option<(*mut Block, usize)> {
+    if ptr.is_null() {
+        return None;
+    }
+    let owned = large_block(ptr, handle.large).is_some() || parent_heap(ptr, handle.heap).is_some();
+    let base = if owned {
+        ptr as *mut c_void
+    } else {
+        aligned_base(handle, ptr)?
+    };
+    let block = unsafe { block_unshift!(base) };
+    if unsafe { (*block).is_free() } {
+        return None;
+    }
+    Some((block, ptr as usize - base as usize))
+}
+
+/// Whether `size` can be what the allocation at `ptr` was made with: no more
+/// than it holds. Pointers `handle` did not hand out are left for the free to
+/// refuse.
+fn size_matches(handle: &HeapHandle, ptr: *const c_void, size: usize) -> bool {
+    heap_usable_size(handle, ptr).is_none_or(|usable| size <= usable)
+}
+
+/// Bytes usable behind `ptr`, or `None` if it was not handed out by `handle`.
+fn heap_usable_size(handle: &HeapHandle, ptr: *const c_void) -> Option<usize> {
+    let (block, offset) = live_block(handle, ptr)?;
+    Some(unsafe { (*block).data_size() } - REDZONE - offset)
+}
+
+/// Unmaps everything `handle` has mapped, live allocations included, and
+/// leaves it as good as new.
+fn heap_reset(handle: &mut HeapHandle) {
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            let next = (*heap).next;
+            let _ = mem_unmap(heap as *const c_void, (*heap).total_size);
+            heap = next;
+        }
+    }
+    let mut block = handle.large;
+    while !block.is_null() {
+        unsafe {
+            let next = (*block).next as *mut Block;
+            let (start, length) = large_mapping(block);
+            let _ = mem_unmap(start, length);
+            block = next;
+        }
+    }
+    handle.heap = ptr::null_mut();
+    handle.large = ptr::null_mut();
+    // Only the large-mapping cache is left.
+    heap_trim(handle);
+    *handle = HeapHandle::with_config(handle.config);
+}
+
+/// Unmaps every heap of `handle` that holds no live block, and every cached
+/// large mapping. Returns the number of bytes given back to the OS.
+fn heap_trim(handle: &mut HeapHandle) -> usize {
+    let mut released = 0;
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            let next = (*heap).next;
+            if heap_is_empty(heap) {
+                unlink_heap(heap, handle);
+                let size = (*heap).total_size;
+                if mem_unmap(heap as *const c_void, size).is_ok() {
+                    released += size;
+                }
+            }
+            heap = next;
+        }
+    }
+    while !handle.large_cache.is_null() {
+        let block = handle.large_cache;
+        unsafe {
+            handle.large_cache = (*block).next as *mut Block;
+            let (start, length) = large_mapping(block);
+            if mem_unmap(start, length).is_ok() {
+                released += length;
+            }
+        }
+    }
+    handle.large_cache_len = 0;
+    handle.large_cache_bytes = 0;
+    released
+}
+
+/// Hands the pages of every free block, the untouched tail of every heap and
+/// every cached large mapping of `handle` back to the OS, keeping them mapped
+/// for reuse. Returns the number of bytes discarded.
+fn heap_discard(handle: &mut HeapHandle) -> usize {
+    let mut discarded = 0;
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            let mut block = heap_shift!(heap).cast::<Block>();
+            let mut end = block as usize;
+            if (*heap).block_count > 0 {
+                while !block.is_null() {
+                    end = block_shift!(block) as usize + (*block).data_size();
+                    if (*block).is_free() {
+                        discarded += discard_data(block);
+                    }
+                    block = (*block).next as *mut Block;
+                }
+            }
+            discarded += discard_range(end, heap as usize + (*heap).total_size);
+            heap = (*heap).next;
+        }
+    }
+    let mut block = handle.large_cache;
+    while !block.is_null() {
+        discarded += discard_data(block);
+        block = unsafe { (*block).next as *mut Block };
+    }
+    discarded
+}
+
+/// Merges every run of adjacent free blocks in one sweep, hands a free last
+/// block back to the untouched tail of its heap, and refiles the free blocks
+/// that are left. Freeing a block already folds in its free neighbours, so a
+/// run only turns up in a chain whose blocks were marked free without that
+/// step. Live blocks never move. Returns the number of bytes folded into a
+/// neighbour or a heap's tail.
+fn heap_defragment(handle: &mut HeapHandle) -> usize {
+    let mut coalesced = 0;
+    let mut heap = handle.heap;
+    while !heap.is_null() {
+        unsafe {
+            if (*heap).block_count > 0 {
+                (*heap).bins = [ptr::null_mut(); BIN_COUNT];
+                let mut last = heap_shift!(heap).cast::<Block>();
+                let mut block = last;
+                while !block.is_null() {
+                    if (*block).is_free() {
+                        let before = (*block).data_size();
+                        merge_right(block, heap);
+                        coalesced += (*block).data_size() - before;
+                    }
+                    last = block;
+                    block = (*block).next as *mut Block;
+                }
+                if (*last).is_free() {
+                    // Its bytes already count as free, so only the chain
+                    // changes.
+                    coalesced += (*last).data_size() + Block::size();
+                    let previous = (*last).previous as *mut Block;
+                    if !previous.is_null() {
+                        (*previous).next = null();
+                    }
+                    (*heap).last = previous;
+                    (*heap).block_count -= 1;
+                }
+                let mut block = heap_shift!(heap).cast::<Block>();
+                while (*heap).block_count > 0 && !block.is_null() {
+                    if (*block).is_free() {
+                        push_free_block(heap, block);
+                    }
+                    block = (*block).next as *mut Block;
+                }
+            }
+            heap = (*heap).next;
+        }
+    }
+    coalesced
+}
+
+/// Discards the whole pages inside the data of a free block. The page holding
+/// its header stays.
+fn discard_data(block: *mut Block) -> usize {
+    let data = unsafe { block_shift!(block) } as usize;
+    discard_range(data, data + unsafe { (*block).data_size() })
+}
+
+/// Discards the whole pages between `start` and `end`.
+fn discard_range(start: usize, end: usize) -> usize {
+    let page = get_page_size();
+    let start = start.next_multiple_of(page);
+    let end = end & !(page - 1);
+    if end <= start {
+        return 0;
+    }
+    match mem_discard(start as *const c_void, end - start) {
+        Ok(()) => end - start,
+        Err(_) => 0,
+    }
+}
+
+/// Cuts a used block down to `size` bytes, turning the remainder into a free
+/// block when it is big enough to hold a header and the configured minimum of
+/// bytes. Free blocks only have to fit `size` itself, so a smaller remainder,
+/// down to none at all, stays part of the block.
+fn split_block(block: *mut Block, heap: *mut Heap, size: usize, config: &AllocatorConfig) {
+    unsafe {
+        debug_assert!((*block).data_size() >= size);
+        if (*block).data_size() - size < Block::size().saturating_add(config.min_split) {
+            return;
+        }
+        let tail = block_shift!(block).add(size).cast::<Block>();
+        let mut tail_header = Block::new((*block).data_size() - size - Block::size());
+        tail_header.set_free(true);
+        tail_header.previous = block;
+        tail_header.next = (*block).next;
+        tail.write(tail_header);
+        if (*block).next.is_null() {
+            (*heap).last = tail;
+        } else {
+            (*((*block).next as *mut Block)).previous = tail;
+        }
+        (*block).next = tail;
+        (*block).set_data_size(size);
+        (*heap).block_count += 1;
+        (*heap).free_size += (*tail).data_size() + Block::size();
+        merge_right(tail, heap);
+        push_free_block(heap, tail);
+    }
+}
+
+/// Grows a used heap block to `needed` bytes by absorbing its right
+/// neighbour, if that one is free and big enough, and gives back whatever
+/// the neighbour had in excess. Touches nothing when it returns false.
+fn grow_block(
+    block: *mut Block,
+    heap: *mut Heap,
+    needed: usize,
+    config: &AllocatorConfig,
+) -> bool {
+    unsafe {
+        let next = (*block).next as *mut Block;
+        if next.is_null()
+            || !(*next).is_free()
+            || (*block).data_size() + Block::size() + (*next).data_size() < needed
+        {
+            return false;
+        }
+        (*heap).free_size -= (*next).data_size() + Block::size();
+        merge_right(block, heap);
+        split_block(block, heap, needed, config);
+        arm_redzone(block);
+    }
+    true
+}
+
+/// Makes the allocation at `ptr` hold `size` bytes without moving it, using
+/// the slack in its block or a free right neighbour. Returns false, having
+/// changed nothing, when neither is enough.
+fn heap_grow_in_place(handle: &mut HeapHandle, ptr: *mut c_void, size: usize) -> bool {
+    let Some(needed) = align(MIN_ALIGN, size).and_then(|size| size.checked_add(REDZONE)) else {
+        return false;
+    };
+    let block = unsafe { block_unshift!(ptr) };
+    if large_block(ptr, handle.large).is_some() {
+        // Standalone mappings can only be reused as they are.
+        return needed <= unsafe { (*block).data_size() };
+    }
+    let Some(heap) = parent_heap(ptr, handle.heap) else {
+        return false;
+    };
+    let before = unsafe { (*block).data_size() };
+    if unsafe { (*block).is_free() } {
+        return false;
+    }
+    if needed <= before {
+        return true;
+    }
+    if !grow_block(block, heap, needed, &handle.config) {
+        return false;
+    }
+    track_live(handle, before, unsafe { (*block).data_size() });
+    true
+}
+
+/// Cuts the allocation at `ptr` down to `size` bytes in place, handing the
+/// rest of its block back to the heap when that leaves a usable free block.
+/// Returns false, having changed nothing, when `size` is more than it holds.
+fn heap_shrink_in_place(handle: &mut HeapHandle, ptr: *mut c_void, size: usize) -> bool {
+    let Some(needed) = align(MIN_ALIGN, size).and_then(|size| size.checked_add(REDZONE)) else {
+        return false;
+    };
+    let block = unsafe { block_unshift!(ptr) };
+    if large_block(ptr, handle.large).is_some() {
+        // Standalone mappings can only be reused as they are.
+        return needed <= unsafe { (*block).data_size() };
+    }
+    let Some(heap) = parent_heap(ptr, handle.heap) else {
+        return false;
+    };
+    let before = unsafe { (*block).data_size() };
+    if unsafe { (*block).is_free() } || before < needed {
+        return false;
+    }
+    split_block(block, heap, needed, &handle.config);
+    arm_redzone(block);
+    track_live(handle, before, unsafe { (*block).data_size() });
+    true
+}
+
+fn heap_realloc(handle: &mut HeapHandle, ptr: *mut c_void, size: usize) -> *mut c_void {
+    heap_try_realloc(handle, ptr, size).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Resizes like [`heap_realloc`], reporting a pointer the handle does not own
+/// instead of panicking. A rejected pointer is left untouched.
+fn heap_try_realloc(
+    handle: &mut HeapHandle,
+    ptr: *mut c_void,
+    size: usize,
+) -> Result<*mut c_void, FreeError> {
+    if ptr.is_null() {
+        return Ok(heap_alloc(handle, size));
+    }
+    if size == 0 {
+        heap_free(handle, ptr)?;
+        return Ok(ptr::null_mut());
+    }
+    let Some((size, needed)) = align(MIN_ALIGN, size)
+        .and_then(|size| Some((size, size.checked_add(REDZONE)?)))
+    else {
+        // Like C realloc: the old allocation stays valid.
+        return Ok(ptr::null_mut());
+    };
+    let block = unsafe { block_unshift!(ptr) };
+    let mut base = ptr;
+    if large_block(ptr, handle.large).is_some() {
+        // Standalone mappings are reused as they are, or grown by the OS.
+        if needed <= unsafe { (*block).data_size() } {
+            return Ok(ptr);
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(block) = remap_large(handle, block, needed) {
+            return Ok(unsafe { block_shift!(block) }.cast());
+        }
+    } else if let Some(heap) = parent_heap(ptr, handle.heap) {
+        let before = unsafe { (*block).data_size() };
+        if needed <= before {
+            split_block(block, heap, needed, &handle.config);
+            arm_redzone(block);
+            track_live(handle, before, unsafe { (*block).data_size() });
+            return Ok(ptr);
+        }
+        if grow_block(block, heap, needed, &handle.config) {
+            track_live(handle, before, unsafe { (*block).data_size() });
+            return Ok(ptr);
+        }
+    } else if let Some(aligned) = aligned_base(handle, ptr) {
+        base = aligned;
+    } else {
+        return Err(FreeError::InvalidPointer);
+    }
+    let layout = Layout::from_size_align(size, 1).expect("sizes are checked above");
+    Ok(heap_move(handle, ptr, base, layout))
+}
+
+/// Grows a large block to hold `needed` bytes by resizing its mapping, which
+/// the OS moves without copying when it cannot grow where it is. Returns the
+/// block at its new address, or `None` when it has to be copied instead.
+#[cfg(target_os = "linux")]
+fn remap_large(handle: &mut HeapHandle, block: *mut Block, needed: usize) -> Option<*mut Block> {
+    unsafe {
+        // Neither a guard page nor the alignment of the data would survive.
+        if (*block).is_guarded() || block_lead(block) != 0 {
+            return None;
+        }
+        let (start, length) = large_mapping(block);
+        let new_length = needed.checked_add(Block::size())?;
+        let used = (*block).data_size() - REDZONE;
+        let moved = mem_remap(start, length, new_length).ok()? as *mut Block;
+        track_live(handle, (*moved).data_size(), needed);
+        (*moved).set_data_size(needed);
+        let (previous, next) = ((*moved).previous as *mut Block, (*moved).next as *mut Block);
+        if previous.is_null() {
+            handle.large = moved;
+        } else {
+            (*previous).next = moved;
+        }
+        if !next.is_null() {
+            (*next).previous = moved;
+        }
+        if cfg!(feature = "poison") {
+            block_shift!(moved).add(used).write_bytes(FRESH_BYTE, needed - used);
+        }
+        arm_redzone(moved);
+        Some(moved)
+    }
+}
+
+/// Moves the allocation at `ptr`, inside the block whose data starts at
+/// `base`, to a new one for `layout`, taking its bytes and tag along, and
+/// frees the old block. Returns null, keeping the old allocation, when no
+/// memory is left.
+fn heap_move(
+    handle: &mut HeapHandle,
+    ptr: *mut c_void,
+    base: *mut c_void,
+    layout: Layout,
+) -> *mut c_void {
+    let offset = ptr as usize - base as usize;
+    let old = unsafe { block_unshift!(base) };
+    let old_size = unsafe { (*old).data_size() } - REDZONE - offset;
+    handle.tag = unsafe { (*old).tag() };
+    let new_ptr = heap_allocate(handle, layout);
+    handle.tag = 0;
+    if new_ptr.is_null() {
+        // Like C realloc: the old allocation stays valid.
+        return new_ptr;
+    }
+    let copied = old_size.min(layout.size());
+    unsafe { ptr::copy_nonoverlapping(ptr.cast::<u8>(), new_ptr.cast::<u8>(), copied) };
+    // `base` was validated by the caller, so releasing it can only fail on a
+    // corrupted canary, which realloc has no way to report.
+    let _ = heap_free(handle, base);
+    new_ptr
+}
+
+/// Resizes the allocation at `ptr` like [`heap_realloc`], keeping it aligned
+/// to `layout.align()`. In place when the block, or its free right
+/// neighbour, leaves room past the offset of the aligned address.
+fn heap_aligned_realloc(handle: &mut HeapHandle, ptr: *mut c_void, layout: Layout) -> *mut c_void {
+    let (size, alignment) = (layout.size(), layout.align());
+    if alignment <= MIN_ALIGN {
+        return heap_realloc(handle, ptr, size);
+    }
+    if ptr.is_null() {
+        return heap_allocate(handle, layout);
+    }
+    if size == 0 {
+        if let Err(err) = heap_free(handle, ptr) {
+            panic!("{err}")
+        }
+        return ptr::null_mut();
+    }
+    let Some(needed) = align(MIN_ALIGN, size).and_then(|size| size.checked_add(REDZONE)) else {
+        return ptr::null_mut();
+    };
+    let owned = large_block(ptr, handle.large).is_some() || parent_heap(ptr, handle.heap).is_some();
+    let base = match owned {
+        true => ptr,
+        false => aligned_base(handle, ptr).unwrap_or_else(|| panic!("invalid pointer")),
+    };
+    // The offset of the aligned address comes first, and stays put.
+    let offset = ptr as usize - base as usize;
+    let block = unsafe { block_unshift!(base) };
+    if (ptr as usize).is_multiple_of(alignment) {
+        if let Some(total) = offset.checked_add(needed) {
+            if large_block(base, handle.large).is_some() {
+                // Standalone mappings can only be reused as they are.
+                if total <= unsafe { (*block).data_size() } {
+                    return ptr;
+                }
+            } else if let Some(heap) = parent_heap(base, handle.heap) {
+                let before = unsafe { (*block).data_size() };
+                if total <= before {
+                    split_block(block, heap, total, &handle.config);
+                    arm_redzone(block);
+                    track_live(handle, before, unsafe { (*block).data_size() });
+                    return ptr;
+                }
+                if grow_block(block, heap, total, &handle.config) {
+                    track_live(handle, before, unsafe { (*block).data_size() });
+                    return ptr;
+                }
+            }
+        }
+    }
+    heap_move(handle, ptr, base, layout)
+}
+
+/// Allocates a block satisfying `layout` from the calling thread's arena.
+/// Every allocating entry point ends up here.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub(crate) fn allocate(layout: Layout) -> *mut c_void {
+    allocate_tagged(layout, 0)
+}
+
+#[cfg_attr(feature = "leak-check", track_caller)]
+fn allocate_tagged(layout: Layout, tag: u32) -> *mut c_void {
+    let site = call_site();
+    let (ptr, checked) = arena::with_arena(|handle| {
+        handle.site = site;
+        handle.tag = tag;
+        let ptr = heap_allocate(handle, layout);
+        handle.tag = 0;
+        (ptr, debug_verify(handle))
+    });
+    if let Err(corruption) = checked {
+        panic!("{corruption}");
+    }
+    remember_size(ptr, layout.size());
+    ptr
+}
+
+/// Releases a block handed out by [`allocate`] for `layout`.
+///
+/// # Safety
+///
+/// Same as [`free`].
+pub(crate) unsafe fn deallocate(ptr: *mut c_void, layout: Layout) -> Result<(), FreeError> {
+    let result = arena::with_owner(ptr, |handle| {
+        debug_assert!(size_matches(handle, ptr, layout.size()));
+        heap_free(handle, ptr)
+    })
+    .unwrap_or(Err(FreeError::InvalidPointer));
+    if result.is_ok() {
+        forget_size(ptr);
+    }
+    result
+}
+
+/// Allocates `size` bytes and returns a pointer to the start of the
+/// allocation.
+///
+/// The returned memory is uninitialized and aligned to 16 bytes. It must be
+/// released with [`free`] (or resized with [`realloc`]). A `size` of zero
+/// still returns a unique pointer that must be freed.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn malloc(size: usize) -> *mut c_void {
+    match Layout::from_size_align(size, 1) {
+        Ok(layout) => allocate(layout),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Like [`malloc`], but returns `None` where `malloc` would return null: when
+/// the OS has no memory left or `size` is too large to ever fit.
+///
+/// The memory is released with [`free`] like any other allocation.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn try_malloc(size: usize) -> Option<NonNull<u8>> {
+    NonNull::new(malloc(size).cast())
+}
+
+/// Allocates at least `len` bytes and hands them out as a zeroed slice, for
+/// callers that would rather not handle raw pointers. The slice spans the
+/// whole usable size of the allocation, which may be more than `len`.
+///
+/// The memory stays allocated until the slice is given back to
+/// [`free_slice`]; dropping the slice leaks it. Returns `None` where
+/// [`malloc`] would return null.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn alloc_slice(len: usize) -> Option<&'static mut [u8]> {
+    let ptr = try_malloc(len)?.as_ptr();
+    let usable = malloc_usable_size(ptr.cast());
+    unsafe {
+        ptr.write_bytes(0, usable);
+        Some(slice::from_raw_parts_mut(ptr, usable))
+    }
+}
+
+/// Releases a slice handed out by [`alloc_slice`].
+///
+/// Only a whole slice is taken back. Anything else, a part of one included,
+/// is refused with [`FreeError::InvalidPointer`] and stays allocated.
+pub fn free_slice(slice: &'static mut [u8]) -> Result<(), FreeError> {
+    let ptr = slice.as_mut_ptr().cast::<c_void>();
+    let len = slice.len();
+    let result = arena::with_owner(ptr, |handle| {
+        // A pointer into a block may pass for an over-aligned allocation, so
+        // only block starts count.
+        let whole = (large_block(ptr, handle.large).is_some()
+            || parent_heap(ptr, handle.heap).is_some())
+            && heap_usable_size(handle, ptr) == Some(len);
+        match whole {
+            true => heap_free(handle, ptr),
+            false => Err(FreeError::InvalidPointer),
+        }
+    })
+    .unwrap_or(Err(FreeError::InvalidPointer));
+    if result.is_ok() {
+        forget_size(ptr);
+    }
+    result
+}
+
+/// Maps the emergency pool that [`try_malloc_emergency`] falls back on, or
+/// tops it up if it is running low. Call it at startup, while memory is
+/// still plentiful; the pool refills by itself afterwards whenever an
+/// emergency allocation finds memory available.
+///
+/// Returns false when the OS has no memory left to map the pool with.
+pub fn emergency_reserve() -> bool {
+    arena::with_emergency(refill_emergency)
+}
+
+/// Like [`try_malloc`], but falls back on the emergency pool set aside by
+/// [`emergency_reserve`] when the OS has no memory left, so that error paths
+/// can still allocate, to report the failure for instance. The pool serves
+/// requests of up to 512 bytes.
+///
+/// The memory is released with [`free`] like any other allocation.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn try_malloc_emergency(size: usize) -> Option<NonNull<u8>> {
+    if let Some(ptr) = try_malloc(size) {
+        emergency_reserve();
+        return Some(ptr);
+    }
+    let site = call_site();
+    let ptr = arena::with_emergency(|handle| {
+        handle.site = site;
+        heap_alloc(handle, size)
+    });
+    remember_size(ptr, size);
+    NonNull::new(ptr.cast())
+}
+
+/// Allocates `size` bytes in a mapping of their own whose pages come from
+/// NUMA node `node`, for large buffers worked on by threads pinned to that
+/// node. Where the pages cannot be bound, on a machine without such a node or
+/// off Linux, the allocation is made all the same, unbound.
+///
+/// The memory is released with [`free`] like any other allocation.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn alloc_large_on_node(size: usize, node: usize) -> *mut c_void {
+    let Some(needed) = align(MIN_ALIGN, size.max(1)).and_then(|size| size.checked_add(REDZONE))
+    else {
+        return ptr::null_mut();
+    };
+    let site = call_site();
+    let ptr = arena::with_arena(|handle| {
+        handle.site = site;
+        let ptr = alloc_large(handle, needed, Mapping::current());
+        if !ptr.is_null() {
+            bind_large(unsafe { block_unshift!(ptr) }, node);
+        }
+        hand_out(handle, ptr)
+    });
+    remember_size(ptr, size);
+    ptr
+}
+
+/// Binds the pages of a large block's mapping to NUMA node `node`, moving
+/// those already in use. Returns whether the OS agreed.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn bind_large(block: *const Block, node: usize) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        let (start, length) = large_mapping(block);
+        mem_bind(start, length, node).is_ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    false
+}
+
+/// Like [`malloc`], but files the allocation under `tag`, a category of the
+/// caller's choosing, so that [`bytes_by_tag`] can tell which part of a
+/// program holds how much memory. [`realloc`] keeps the tag.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn malloc_tagged(size: usize, tag: u32) -> *mut c_void {
+    match Layout::from_size_align(size, 1) {
+        Ok(layout) => allocate_tagged(layout, tag),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Allocates `count` blocks of `size` bytes at once, for pools of equal
+/// objects. Blocks small enough for a heap are carved out of a single new
+/// mapping under a single lock; bigger ones get a mapping each, as with
+/// [`malloc`]. Every pointer is released with [`free`] on its own.
+///
+/// Returns an empty vector, having allocated nothing, when the OS has no
+/// memory left.
+#[cfg(feature = "std")]
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn alloc_batch(size: usize, count: usize) -> Vec<*mut c_void> {
+    // Allocated with no arena locked, so that filling it allocates nothing.
+    let mut ptrs = Vec::new();
+    if ptrs.try_reserve_exact(count).is_err() {
+        return ptrs;
+    }
+    let site = call_site();
+    let checked = arena::with_arena(|handle| {
+        handle.site = site;
+        heap_alloc_batch(handle, size, count, &mut ptrs);
+        debug_verify(handle)
+    });
+    if let Err(corruption) = checked {
+        panic!("{corruption}");
+    }
+    for &ptr in &ptrs {
+        remember_size(ptr, size);
+    }
+    ptrs
+}
+
+/// Live bytes by the tag given to [`malloc_tagged`], across every arena.
+/// Allocations made any other way count under tag 0.
+#[cfg(feature = "std")]
+pub fn bytes_by_tag() -> HashMap<u32, usize> {
+    // The map is allocated with no arena locked, with room for a tag per
+    // live block so that filling it allocates nothing. Blocks allocated in
+    // between may be left out.
+    let mut live = 0;
+    arena::for_each_arena(|handle| {
+        heap_blocks(handle, &mut |info| live += usize::from(!info.free))
+    });
+    let mut bytes = HashMap::with_capacity(live);
+    arena::for_each_arena(|handle| {
+        heap_blocks(handle, &mut |info| {
+            let tag = unsafe { (*(block_unshift!(info.ptr))).tag() };
+            if !info.free && (bytes.len() < live || bytes.contains_key(&tag)) {
+                *bytes.entry(tag).or_insert(0) += info.size;
+            }
+        })
+    });
+    bytes
+}
+
+/// Allocates zeroed memory for `count` elements of `size` bytes each.
+///
+/// Returns null if `count * size` overflows. Large requests that get a new
+/// mapping are not written to at all, since the OS hands out zeroed pages.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn calloc(count: usize, size: usize) -> *mut c_void {
+    let Some(total) = count.checked_mul(size).filter(|&total| total <= isize::MAX as usize)
+    else {
+        return ptr::null_mut();
+    };
+    let site = call_site();
+    let ptr = arena::with_arena(|handle| {
+        handle.site = site;
+        heap_calloc(handle, total)
+    });
+    remember_size(ptr, total);
+    ptr
+}
+
+/// Allocates `size` bytes aligned to `alignment`, which must be a power of
+/// two. Returns null for any other alignment.
+///
+/// The allocation is released with [`free`] like any other.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
+    match Layout::from_size_align(size, alignment) {
+        Ok(layout) => allocate(layout),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Resizes an allocation like [`realloc`], but keeps it aligned to
+/// `alignment`, which must be a power of two, where `realloc` only
+/// guarantees 16 bytes. The allocation grows in place when its block has
+/// room, and moves to a new block with the same alignment otherwise.
+///
+/// Returns null, leaving `ptr` untouched, for any other alignment. A null
+/// `ptr` behaves like [`aligned_alloc`], and a `size` of zero frees `ptr`.
+///
+/// # Safety
+///
+/// Same as [`realloc`].
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub unsafe fn aligned_realloc(ptr: *mut c_void, alignment: usize, size: usize) -> *mut c_void {
+    let Ok(layout) = Layout::from_size_align(size, alignment) else {
+        return ptr::null_mut();
+    };
+    if ptr.is_null() {
+        return allocate(layout);
+    }
+    let site = call_site();
+    let new_ptr = arena::with_owner(ptr, |handle| {
+        handle.site = site;
+        heap_aligned_realloc(handle, ptr, layout)
+    })
+    .unwrap_or_else(|| panic!("invalid pointer"));
+    if size == 0 || !new_ptr.is_null() {
+        forget_size(ptr);
+        remember_size(new_ptr, size);
+    }
+    new_ptr
+}
+
+/// Returns how many bytes can actually be used behind `ptr`, which may be
+/// more than were requested.
+///
+/// Returns 0 for null and for pointers this allocator does not own.
+pub fn malloc_usable_size(ptr: *const c_void) -> usize {
+    arena::with_owner(ptr, |handle| heap_usable_size(handle, ptr))
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Whether `ptr` is a live allocation handed out by [`malloc`] and friends,
+/// from any thread. Freed pointers and pointers into the middle of an
+/// allocation are not.
+pub fn contains(ptr: *const c_void) -> bool {
+    arena::with_owner(ptr, |handle| heap_usable_size(handle, ptr).is_some()).unwrap_or(false)
+}
+
+/// Caps how many bytes of freed large allocations each arena keeps mapped
+/// for reuse. Zero turns the cache off; the cap applies from the next free.
+pub fn set_large_cache_limit(bytes: usize) {
+    LARGE_CACHE_CAP.store(bytes, Ordering::Relaxed);
+}
+
+/// Keeps heaps with no live block mapped for reuse, as long as those of an
+/// arena add up to no more than `bytes`, instead of unmapping each as soon as its
+/// last block is freed. A lone empty heap always stays mapped, and [`trim`]
+/// releases the rest either way. Zero, the default, keeps no others.
+pub fn set_trim_threshold(bytes: usize) {
+    TRIM_THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+/// Holds the last `blocks` freed in each arena back from reuse, filled with
+/// a pattern, so that a use after free reads garbage instead of the next
+/// owner's data. Writes to a held block are reported, by panicking in
+/// [`free`], when it is released. At most 64 blocks are held; zero, the
+/// default, holds none. Held blocks count as live in [`stats`].
+pub fn set_quarantine(blocks: usize) {
+    let config = AllocatorConfig::new().quarantine(blocks);
+    QUARANTINE.store(config.quarantine, Ordering::Relaxed);
+}
+
+/// Rounds requests of up to `bytes` up to the next power of two, see
+/// [`AllocatorConfig::round_to_powers_of_two`]. Zero, the default, only
+/// rounds to multiples of 16.
+pub fn set_size_rounding(bytes: usize) {
+    ROUNDING_CAP.store(bytes, Ordering::Relaxed);
+}
+
+/// Splits a free block picked for a smaller request, or a block shrunk in
+/// place, only when the rest would hold at least `bytes`; see
+/// [`AllocatorConfig::min_split`]. The default is 32.
+pub fn set_min_split(bytes: usize) {
+    let config = AllocatorConfig::new().min_split(bytes);
+    MIN_SPLIT.store(config.min_split, Ordering::Relaxed);
+}
+
+/// Gives requests above `bytes` a mapping of their own instead of a block in
+/// a heap. The default is 128 KiB. Returns false, changing nothing, when
+/// `bytes` is no more than the biggest small block, 1 KiB: requests that
+/// small always come from shared heaps.
+pub fn set_large_cutoff(bytes: usize) -> bool {
+    if bytes <= AllocatorConfig::new().small_block_size() {
+        return false;
+    }
+    LARGE_CUTOFF.store(bytes, Ordering::Relaxed);
+    true
+}
+
+/// The sizes of the arenas behind the global entry points: the defaults, as
+/// tuned by [`set_trim_threshold`], [`set_min_split`], [`set_large_cutoff`],
+/// [`set_quarantine`] and [`set_size_rounding`]. Arenas pick them up whenever they are locked.
+fn default_config() -> AllocatorConfig {
+    AllocatorConfig::new()
+        .large_threshold(LARGE_CUTOFF.load(Ordering::Relaxed))
+        .trim_threshold(TRIM_THRESHOLD.load(Ordering::Relaxed))
+        .min_split(MIN_SPLIT.load(Ordering::Relaxed))
+        .quarantine(QUARANTINE.load(Ordering::Relaxed))
+        .round_to_powers_of_two(ROUNDING_CAP.load(Ordering::Relaxed))
+}
+
+/// Returns memory that no live allocation uses to the OS: heaps whose blocks
+/// are all free and cached large mappings, across every arena. After
+/// [`set_trim_discards`] the pages of every free block are given back instead,
+/// and nothing is unmapped.
+///
+/// Returns the number of bytes released. Safe to call at any time.
+pub fn trim() -> usize {
+    let discard = TRIM_DISCARDS.load(Ordering::Relaxed);
+    let mut released = 0;
+    arena::for_each_arena(|handle| {
+        released += match discard {
+            true => heap_discard(handle),
+            false => heap_trim(handle),
+        }
+    });
+    released
+}
+
+/// Maps heaps for `count` allocations of `size` bytes in the calling thread's
+/// arena ahead of time, so that making them later needs no new mapping.
+///
+/// Returns false when `size` is above the large cutoff, as those allocations
+/// get a mapping of their own each, or when the OS has no memory left. Heaps
+/// that no allocation uses yet are released by [`trim`] like any other empty
+/// heap.
+pub fn reserve(size: usize, count: usize) -> bool {
+    arena::with_arena(|handle| heap_reserve(handle, size, count))
+}
+
+/// Sweeps every heap for free blocks that sit next to each other and merges
+/// them, so that scattered free space can serve larger requests again. A free
+/// block at the end of a heap goes back to the heap's unused tail, and every
+/// heap's bins are rebuilt. Live allocations are never moved.
+///
+/// Returns the number of bytes merged into a neighbour or a heap's tail.
+pub fn defragment() -> usize {
+    let mut coalesced = 0;
+    arena::for_each_arena(|handle| coalesced += heap_defragment(handle));
+    coalesced
+}
+
+/// When a new mapping fails, unmaps the empty heaps and cached large mappings
+/// of the arena asking and tries once more before giving up, so the
+/// allocator falls back on its own spare memory before reporting that it is
+/// out of memory. Off by default.
+pub fn set_trim_on_oom(enabled: bool) {
+    TRIM_ON_OOM.store(enabled, Ordering::Relaxed);
+}
+
+/// Makes [`trim`] hand the physical pages of free memory back to the OS with
+/// `madvise(MADV_DONTNEED)` while keeping the mappings, so reusing them needs
+/// no new mapping. This also reaches free blocks inside heaps that are still
+/// in use, which unmapping cannot.
+pub fn set_trim_discards(enabled: bool) {
+    TRIM_DISCARDS.store(enabled, Ordering::Relaxed);
+}
+
+/// Tears down every heap of every arena, returning all of the allocator's
+/// memory to the OS. Meant for tests and shutdown, to start over from a
+/// clean allocator.
+///
+/// # Safety
+///
+/// Every pointer handed out so far becomes dangling, so none may be used or
+/// freed afterwards, by this thread or any other.
+pub unsafe fn reset() {
+    arena::for_each_arena(heap_reset);
+    #[cfg(feature = "size-map")]
+    size_map::SIZES.lock().unwrap_or_else(PoisonError::into_inner).clear();
+}
+
+/// Reports how much memory the allocator has mapped and how much of it is in
+/// use, across every arena.
+pub fn stats() -> HeapStats {
+    let mut stats = HeapStats::default();
+    arena::for_each_arena(|handle| heap_stats(handle, &mut stats));
+    stats
+}
+
+/// The most bytes live allocations have held at once since the program
+/// started or [`reset_high_watermark`] last ran, padding included. Each arena
+/// keeps its own peak and this adds them up, so when several threads allocate
+/// it can be more than was ever live at one moment.
+pub fn high_watermark() -> usize {
+    let mut peak = 0;
+    arena::for_each_arena(|handle| peak += handle.peak_bytes);
+    peak
+}
+
+/// Starts [`high_watermark`] over from the bytes live right now.
+pub fn reset_high_watermark() {
+    arena::for_each_arena(|handle| handle.peak_bytes = handle.live_bytes);
+}
+
+/// Live allocations across every arena by size class, to show which arena
+/// sizes suit a program. Each entry pairs the smallest usable size of a class
+/// with the number of live allocations in it. Classes double from 16 bytes
+/// up, and the last one takes everything larger. Allocations count by the
+/// usable size of their block, which may be more than was asked for.
+pub fn size_class_histogram() -> [(usize, usize); BIN_COUNT] {
+    let mut counts = [0; BIN_COUNT];
+    arena::for_each_arena(|handle| {
+        for (total, count) in counts.iter_mut().zip(handle.class_counts) {
+            *total += count;
+        }
+    });
+    class_histogram(&counts)
+}
+
+/// Calls `f` with every block of every arena, free ones included.
+///
+/// Each arena stays locked while it is walked, so `f` must not allocate or
+/// free through this crate.
+pub fn for_each_block(mut f: impl FnMut(BlockInfo)) {
+    arena::for_each_arena(|handle| heap_blocks(handle, &mut f));
+}
+
+/// Writes a readable listing of every heap and block of every arena to
+/// `out`: each heap's size class, size, free bytes and block count, followed
+/// by its blocks.
+///
+/// Each arena stays locked while it is written out, so `out` must not
+/// allocate or free through this crate.
+pub fn dump_heap(out: &mut impl fmt::Write) -> fmt::Result {
+    let mut result = Ok(());
+    let mut arena = 0;
+    arena::for_each_arena(|handle| {
+        if result.is_ok() && (!handle.heap.is_null() || !handle.large.is_null()) {
+            result = writeln!(out, "arena {arena}:").and_then(|_| heap_dump(handle, out));
+        }
+        arena += 1;
+    });
+    result
+}
+
+/// Walks every heap and block of every arena checking the invariants the
+/// allocator relies on: blocks lie inside their heap one after another,
+/// their links agree, and each heap's block count, free bytes and free lists
+/// match its blocks. Meant for debugging and fuzzing; a healthy allocator
+/// always passes.
+///
+/// Returns a description of the first violation found.
+#[cfg(feature = "std")]
+pub fn verify_integrity() -> Result<(), String> {
+    let mut result = Ok(());
+    arena::for_each_arena(|handle| {
+        if result.is_ok() {
+            result = heap_verify(handle);
+        }
+    });
+    // Formatted with no arena locked, as that allocates.
+    result.map_err(|corruption| corruption.to_string())
+}
+
+/// Makes every later large allocation end at an inaccessible guard page, so
+/// writing past it faults immediately. Costs a page of address space per
+/// allocation and disables the large-mapping cache for those allocations.
+/// Large allocations aligned beyond 16 bytes are not guarded.
+pub fn set_guard_pages(enabled: bool) {
+    GUARD_PAGES.store(enabled, Ordering::Relaxed);
+}
+
+/// Picks the smallest free block that fits each request instead of the first
+/// one found. Leaves smaller remainders behind, at the cost of scanning the
+/// whole size class. First-fit is the default.
+pub fn set_best_fit(enabled: bool) {
+    BEST_FIT.store(enabled, Ordering::Relaxed);
+}
+
+/// Asks for 2 MiB huge pages for every later large allocation, to take
+/// pressure off the TLB for big buffers. Sizes are rounded up to whole huge
+/// pages; when the system has none available, plain pages are used instead.
+/// Guard pages take precedence.
+pub fn set_huge_pages(enabled: bool) {
+    HUGE_PAGES.store(enabled, Ordering::Relaxed);
+}
+
+/// Faults in every page of later large allocations up front, so
+/// latency-sensitive code does not stall on first touch. Mapping takes longer
+/// instead. Only Linux supports this; elsewhere it is ignored.
+pub fn set_prefault(enabled: bool) {
+    PREFAULT.store(enabled, Ordering::Relaxed);
+}
+
+/// Releases an allocation previously returned by [`malloc`] or [`realloc`].
+///
+/// # Panics
+///
+/// Panics if `ptr` was not handed out by this allocator or was already freed.
+/// Use [`try_free`] where a panic is not acceptable.
+///
+/// # Safety
+///
+/// `ptr` must come from [`malloc`] or [`realloc`] and must not be used after
+/// this call.
+pub unsafe fn free(ptr: *mut c_void) {
+    if let Err(err) = try_free(ptr) {
+        panic!("{err}")
+    }
+}
+
+/// Releases an allocation like [`free`], reporting bad pointers instead of
+/// panicking.
+///
+/// # Safety
+///
+/// Same as [`free`]. Pointers rejected with an error are left untouched.
+pub unsafe fn try_free(ptr: *mut c_void) -> Result<(), FreeError> {
+    let (result, checked) =
+        arena::with_owner(ptr, |handle| (heap_free(handle, ptr), debug_verify(handle)))
+            .unwrap_or((Err(FreeError::InvalidPointer), Ok(())));
+    if let Err(corruption) = checked {
+        panic!("{corruption}");
+    }
+    if result.is_ok() {
+        forget_size(ptr);
+    }
+    result
+}
+
+/// The start of the live allocation `ptr` points into, for scanning memory
+/// for references as a garbage collector does, or null if it points into
+/// none. Any address within the usable bytes will do.
+///
+/// For memory from [`aligned_alloc`] this is the start of the block, which
+/// may come before the pointer handed out; [`free`] takes either.
+pub fn get_allocation_base(ptr: *const c_void) -> *mut c_void {
+    arena::with_arena_where(
+        |handle| in_mapped_range(handle, ptr),
+        |handle| heap_allocation_base(handle, ptr),
+    )
+    .flatten()
+    .unwrap_or(ptr::null_mut())
+}
+
+/// A pointer from [`malloc_tracked`] along with the generation of its block,
+/// for debugging frees that come too late.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackedPtr {
+    pub ptr: *mut c_void,
+    pub generation: u16,
+}
+
+/// Like [`malloc`], but returns the pointer with the generation of its
+/// block, so that [`free_tracked`] can tell whether the memory has been
+/// freed and handed out again in the meantime, which [`free`] takes for a
+/// valid pointer. Every free moves the generation on; it wraps around after
+/// 65536 frees within an arena.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub fn malloc_tracked(size: usize) -> TrackedPtr {
+    let ptr = malloc(size);
+    let generation = arena::with_owner(ptr, |handle| {
+        live_block(handle, ptr).map(|(block, _)| unsafe { (*block).generation() })
+    });
+    TrackedPtr { ptr, generation: generation.flatten().unwrap_or(0) }
+}
+
+/// Releases an allocation like [`try_free`], refusing with
+/// [`FreeError::Stale`] when its block has been freed and reused since
+/// [`malloc_tracked`] handed it out.
+///
+/// # Safety
+///
+/// Same as [`free`] for `tracked.ptr`, except that freeing it again after its
+/// memory was reused is reported instead of releasing someone else's block.
+pub unsafe fn free_tracked(tracked: TrackedPtr) -> Result<(), FreeError> {
+    let ptr = tracked.ptr;
+    let (result, checked) = arena::with_owner(ptr, |handle| {
+        let result = match live_block(handle, ptr) {
+            Some((block, _)) if unsafe { (*block).generation() } != tracked.generation => {
+                Err(FreeError::Stale)
+            }
+            _ => heap_free(handle, ptr),
+        };
+        (result, debug_verify(handle))
+    })
+    .unwrap_or((Err(FreeError::InvalidPointer), Ok(())));
+    if let Err(corruption) = checked {
+        panic!("{corruption}");
+    }
+    if result.is_ok() {
+        forget_size(ptr);
+    }
+    result
+}
+
+/// Releases an allocation like [`try_free`], given the size it was made with,
+/// as the allocator API's `deallocate` gets it. Debug builds check that the
+/// allocation holds `size` bytes and refuse with [`FreeError::WrongSize`],
+/// leaving it allocated, when it does not; release builds take the caller's
+/// word for it.
+///
+/// # Safety
+///
+/// Same as [`free`].
+pub unsafe fn free_sized(ptr: *mut c_void, size: usize) -> Result<(), FreeError> {
+    if cfg!(debug_assertions) {
+        let matches = arena::with_owner(ptr, |handle| size_matches(handle, ptr, size));
+        if matches == Some(false) {
+            return Err(FreeError::WrongSize);
+        }
+    }
+    try_free(ptr)
+}
+
+/// Resizes the allocation at `ptr` to `size` bytes, returning the new
+/// location. The first `min(old_size, size)` bytes are preserved.
+///
+/// The block is resized in place when it can absorb a free right neighbour
+/// or is being shrunk. A null `ptr` behaves like [`malloc`], and a `size` of
+/// zero frees `ptr` and returns null.
+///
+/// # Panics
+///
+/// Panics if `ptr` was not handed out by this allocator, or, for a `size` of
+/// zero, was already freed. Use [`try_realloc`] where a panic is not
+/// acceptable.
+///
+/// # Safety
+///
+/// `ptr` must come from [`malloc`] or [`realloc`]. On return the old pointer
+/// is invalid and only the returned one may be used.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub unsafe fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    try_realloc(ptr, size).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Resizes an allocation like [`realloc`], reporting bad pointers instead of
+/// panicking.
+///
+/// # Safety
+///
+/// Same as [`realloc`]. Pointers rejected with an error are left untouched.
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub unsafe fn try_realloc(ptr: *mut c_void, size: usize) -> Result<*mut c_void, FreeError> {
+    if ptr.is_null() {
+        return Ok(malloc(size));
+    }
+    let site = call_site();
+    let new_ptr = arena::with_owner(ptr, |handle| {
+        handle.site = site;
+        heap_try_realloc(handle, ptr, size)
+    })
+    .unwrap_or(Err(FreeError::InvalidPointer))?;
+    // A null result frees `ptr` for a zero size and keeps it otherwise.
+    if size == 0 || !new_ptr.is_null() {
+        forget_size(ptr);
+        remember_size(new_ptr, size);
+    }
+    Ok(new_ptr)
+}
+
+/// Resizes the allocation at `ptr` to hold `count` elements of `size` bytes
+/// each, like [`realloc`].
+///
+/// Returns null and leaves `ptr` untouched if `count * size` overflows.
+///
+/// # Safety
+///
+/// Same as [`realloc`].
+#[cfg_attr(feature = "leak-check", track_caller)]
+pub unsafe fn reallocarray(ptr: *mut c_void, count: usize, size: usize) -> *mut c_void {
+    match count.checked_mul(size) {
+        Some(total) => realloc(ptr, total),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Tries to make the allocation at `ptr` hold `new_size` bytes without
+/// moving it, using spare room in its block or a free block right after it.
+///
+/// Returns false, leaving the allocation untouched, when that is not
+/// possible; [`realloc`] can still move it. Pointers from [`aligned_alloc`]
+/// never grow in place.
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation from [`malloc`] and friends.
+pub unsafe fn grow_in_place(ptr: *mut c_void, new_size: usize) -> bool {
+    let grown = arena::with_owner(ptr, |handle| heap_grow_in_place(handle, ptr, new_size))
+        .unwrap_or(false);
+    if grown {
+        remember_size(ptr, new_size);
+    }
+    grown
+}
+
+/// Shrinks the allocation at `ptr` to `new_size` bytes without moving it,
+/// returning the freed tail of its block to the heap so it can be reused.
+/// Tails too small to hold a block of their own stay with the allocation.
+///
+/// Returns false, leaving the allocation untouched, when `new_size` is more
+/// than it holds or `ptr` came from [`aligned_alloc`].
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation from [`malloc`] and friends.
+pub unsafe fn shrink_in_place(ptr: *mut c_void, new_size: usize) -> bool {
+    let shrunk = arena::with_owner(ptr, |handle| heap_shrink_in_place(handle, ptr, new_size))
+        .unwrap_or(false);
+    if shrunk {
+        remember_size(ptr, new_size);
+    }
+    shrunk
+}
+
+/// Records the requested size of an allocation for [`lookup_size`]. When the
+/// side table cannot grow the size is simply not recorded.
+#[cfg(feature = "size-map")]
+fn remember_size(ptr: *const c_void, size: usize) {
+    if !ptr.is_null() {
+        let mut sizes = size_map::SIZES.lock().unwrap_or_else(PoisonError::into_inner);
+        sizes.insert(ptr, size);
+    }
+}
+
+#[cfg(not(feature = "size-map"))]
+fn remember_size(_ptr: *const c_void, _size: usize) {}
+
+#[cfg(feature = "size-map")]
+fn forget_size(ptr: *const c_void) {
+    size_map::SIZES.lock().unwrap_or_else(PoisonError::into_inner).remove(ptr);
+}
+
+#[cfg(not(feature = "size-map"))]
+fn forget_size(_ptr: *const c_void) {}
+
+/// Returns the size `ptr` was allocated (or last resized) with, looked up in
+/// a side table instead of the block header, so any pointer may be passed.
+/// Returns `None` for pointers that are not live allocations.
+///
+/// Only allocations made through the global entry points are tracked.
+#[cfg(feature = "size-map")]
+pub fn lookup_size(ptr: *const c_void) -> Option<usize> {
+    size_map::SIZES.lock().unwrap_or_else(PoisonError::into_inner).get(ptr)
+}
+
+/// The caller of the public entry point, when `leak-check` is on.
+#[cfg_attr(feature = "leak-check", track_caller)]
+fn call_site() -> Option<&'static Location<'static>> {
+    if cfg!(feature = "leak-check") {
+        Some(Location::caller())
+    } else {
+        None
+    }
+}
+
+/// A live allocation found by [`report_leaks`].
+#[cfg(feature = "leak-check")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leak {
+    pub ptr: *const c_void,
+    /// Usable bytes.
+    pub size: usize,
+    /// Where it was allocated, unknown for memory handed out before the
+    /// call site could be recorded.
+    pub site: Option<&'static Location<'static>>,
+}
+
+#[cfg(feature = "leak-check")]
+fn heap_leaks(handle: &HeapHandle, f: &mut impl FnMut(Leak)) {
+    heap_blocks(handle, &mut |info| {
+        if !info.free {
+            let block = unsafe { block_unshift!(info.ptr) };
+            f(Leak {
+                ptr: info.ptr,
+                size: info.size,
+                site: unsafe { (*block).site },
+            });
+        }
+    });
+}
+
+/// Lists every allocation that has not been freed yet, with the place it was
+/// made. Meant to be called at shutdown.
+#[cfg(feature = "leak-check")]
+pub fn report_leaks() -> Vec<Leak> {
+    // The vector is allocated with no arena locked; anything allocated in
+    // between is left out.
+    let mut count = 0;
+    arena::for_each_arena(|handle| heap_leaks(handle, &mut |_| count += 1));
+    let mut leaks = Vec::with_capacity(count);
+    arena::for_each_arena(|handle| {
+        heap_leaks(handle, &mut |leak| {
+            if leaks.len() < leaks.capacity() {
+                leaks.push(leak);
+            }
+        })
+    });
+    leaks
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::{env, os::unix::process::ExitStatusExt, process::Command};
+    use std::{
+        alloc::Layout,
+        mem::{self, discriminant},
+        os::raw::c_void,
+        ptr, slice,
+        sync::{Mutex, MutexGuard, PoisonError},
+        time::Instant,
+    };
+
+    use crate::{
+        align, aligned_alloc, aligned_realloc, alloc_block, alloc_large, alloc_large_on_node,
+        alloc_slice, allocate, arena, bin_index, bind_large, blocks_of, calloc, contains,
+        create_heap, deallocate, default_config, free, free_sized, free_slice, free_tracked,
+        get_allocation_base, get_free_block, get_last_block, hand_out, heap_alloc, heap_allocate,
+        heap_allocation_base, heap_blocks, heap_calloc, heap_defragment, heap_discard, heap_dump,
+        heap_free, heap_grow_in_place, heap_realloc, heap_reserve, heap_reset, heap_shrink_in_place,
+        heap_stats, heap_trim, heap_try_realloc, heap_usable_size, heap_verify, high_watermark,
+        in_mapped_range, large_block, large_mapping, link_heap, malloc, malloc_tracked,
+        malloc_usable_size, mem_map, mem_protect, mem_unmap, mmap::LIVE_MAPS, mmap::get_page_size,
+        owns, parent_heap, push_free_block, realloc, reallocarray, reset_high_watermark,
+        set_best_fit, set_large_cache_limit, set_large_cutoff, set_min_split, set_prefault,
+        set_trim_on_oom, set_trim_threshold, size_class_histogram, stats, track_large, try_free,
+        try_malloc, try_realloc, unlink_heap, AllocatorConfig, Block, BlockInfo, Corruption,
+        FreeError, GroupStats, Heap, HeapGroup, HeapHandle, HeapStats, Mapping, BIN_COUNT,
+        FREED_BYTE, LARGE_CACHE_BYTES, LARGE_CACHE_ENTRIES, MIN_ALIGN, MIN_REMAINDER, MMAP_NO_FLAGS,
+        MMAP_PROT_NONE, MMAP_PROT_READ_WRITE, PAGE_SIZE, REDZONE, SMALL_HEAP_ALLOCATION_SIZE,
+        TINY_HEAP_ALLOCATION_SIZE,
+    };
+
+    /// Serializes the tests that go through the global entry points, so that
+    /// process-wide settings and panicking frees do not interleave.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn serial() -> MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Marks a block free without coalescing, the way a chain looks when free
+    /// blocks end up side by side.
+    fn release(handle: &HeapHandle, ptr: *mut c_void) {
+        unsafe {
+            let block = block_unshift!(ptr);
+            (*block).set_free(true);
+            (*handle.heap).free_size += (*block).data_size() + Block::size();
+            push_free_block(handle.heap, block);
+        }
+    }
+
+    #[test]
+    fn behavior() {
+        let _serial = serial();
+        let ptr = malloc(10);
+        assert!(!ptr.is_null());
+        // Earlier tests may have left free blocks in this thread's arena, so
+        // only a fresh handle pins the exact block size.
+        assert!(malloc_usable_size(ptr) >= 16);
+        unsafe { free(ptr) };
+
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, 10);
+        let block = unsafe{block_unshift!(ptr) };
+        assert!(unsafe{ (*block).data_size() }  == 16 + REDZONE);
+        heap_free(&mut handle, ptr).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "double free detected")]
+    fn double_free() {
+        let _serial = serial();
+        // Keeps the heap mapped even when the arena is shared with other
+        // tests, as it is without `std`.
+        let _keep = malloc(10);
+        let ptr = malloc(10);
+        assert!(!ptr.is_null());
+        unsafe {
+            free(ptr);
+            free(ptr)
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid pointer")]
+    fn ivalid_free() {
+        let _serial = serial();
+        let ptr = std::ptr::null_mut::<c_void>();
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn realloc_keeps_contents() {
+        let _serial = serial();
+        let ptr = malloc(24) as *mut u8;
+        unsafe {
+            for i in 0..24 {
+                ptr.add(i).write(i as u8);
+            }
+            let grown = realloc(ptr as *mut c_void, 200) as *mut u8;
+            for i in 0..24 {
+                assert_eq!(grown.add(i).read(), i as u8);
+            }
+            free(grown as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn realloc_grows_into_free_neighbour() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 32);
+        let b = heap_alloc(&mut handle, 96);
+        let c = heap_alloc(&mut handle, 16);
+        heap_free(&mut handle, b).unwrap();
+        let grown = heap_realloc(&mut handle, a, 80);
+        assert_eq!(grown, a);
+        let block = unsafe { block_unshift!(grown) };
+        assert_eq!(unsafe { (*block).data_size() }, 80 + REDZONE);
+        heap_free(&mut handle, grown).unwrap();
+        heap_free(&mut handle, c).unwrap();
+    }
+
+    #[test]
+    fn grow_in_place_uses_slack_and_free_neighbours() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 20);
+        let b = heap_alloc(&mut handle, 64);
+        let _c = heap_alloc(&mut handle, 16);
+        let blocks = unsafe { (*handle.heap).block_count };
+
+        // Slack: 20 bytes were rounded up to 32.
+        assert!(heap_grow_in_place(&mut handle, a, 32));
+        assert_eq!(heap_usable_size(&handle, a), Some(32));
+
+        // A used neighbour blocks growth, and nothing changes.
+        let free_size = unsafe { (*handle.heap).free_size };
+        assert!(!heap_grow_in_place(&mut handle, a, 33));
+        assert_eq!(heap_usable_size(&handle, a), Some(32));
+        assert_eq!(unsafe { (*handle.heap).block_count }, blocks);
+        assert_eq!(unsafe { (*handle.heap).free_size }, free_size);
+
+        // A free neighbour is absorbed.
+        heap_free(&mut handle, b).unwrap();
+        let free_size = unsafe { (*handle.heap).free_size };
+        assert!(heap_grow_in_place(&mut handle, a, 80));
+        let grown = heap_usable_size(&handle, a).unwrap();
+        assert!(grown >= 80);
+        assert_eq!(unsafe { (*handle.heap).free_size }, free_size - (grown - 32));
+        let whole = 32 + 64 + Block::size() + 2 * REDZONE;
+        assert!(!heap_grow_in_place(&mut handle, a, whole + 1));
+        heap_free(&mut handle, a).unwrap();
+    }
+
+    #[test]
+    fn shrink_in_place_frees_the_tail() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 1000);
+        let keep = heap_alloc(&mut handle, 200);
+        let before = heap_usable_size(&handle, a).unwrap();
+        let free_size = unsafe { (*handle.heap).free_size };
+        assert!(!heap_shrink_in_place(&mut handle, a, before + 1));
+        assert!(heap_shrink_in_place(&mut handle, a, 100));
+        assert_eq!(heap_usable_size(&handle, a), Some(112));
+
+        let mut blocks = Vec::new();
+        heap_blocks(&handle, &mut |info| blocks.push(info));
+        let tail = unsafe { (a as *mut u8).add(112 + REDZONE + Block::size()) } as *mut c_void;
+        let expected = [
+            (a, 112, false),
+            (tail, before - 112 - Block::size() - REDZONE, true),
+            (keep, 208, false),
+        ]
+        .map(|(ptr, size, free)| BlockInfo { ptr, size, free });
+        assert_eq!(blocks, expected);
+        assert_eq!(unsafe { (*handle.heap).free_size }, free_size + before - 112);
+
+        // Shrinking by less than a block's worth keeps the slack.
+        assert!(heap_shrink_in_place(&mut handle, a, 90));
+        assert_eq!(heap_usable_size(&handle, a), Some(112));
+        assert_eq!(heap_alloc(&mut handle, 500), tail);
+    }
+
+    #[test]
+    fn realloc_moves_when_neighbour_is_used() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 32);
+        let b = heap_alloc(&mut handle, 32);
+        let moved = heap_realloc(&mut handle, a, 64);
+        assert_ne!(moved, a);
+        heap_free(&mut handle, moved).unwrap();
+        heap_free(&mut handle, b).unwrap();
+    }
+
+    #[test]
+    fn node_bound_allocations_fall_back_when_binding_fails() {
+        // Node 0 is there on any machine; node 63 is not on this one.
+        for node in [0, 63] {
+            let ptr = alloc_large_on_node(1 << 20, node).cast::<u8>();
+            assert!(!ptr.is_null());
+            unsafe { ptr.write_bytes(0x5c, 1 << 20) };
+            let standalone = arena::with_owner(ptr.cast(), |handle| {
+                large_block(ptr.cast(), handle.large).is_some()
+            });
+            assert_eq!(standalone, Some(true));
+            unsafe { free(ptr.cast()) };
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs a NUMA machine with a second node"]
+    fn large_blocks_bind_to_a_node() {
+        let mut handle = HeapHandle::new();
+        let ptr = alloc_large(&mut handle, 1 << 20, Mapping::Plain);
+        assert!(bind_large(unsafe { block_unshift!(ptr) }, 1));
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn live_bytes_follow_resizes_and_frees() {
+        let mut handle = HeapHandle::new();
+        let small = heap_alloc(&mut handle, 100);
+        let large = heap_alloc(&mut handle, 1 << 20);
+        let counted = |ptr: *mut c_void| unsafe { (*block_unshift!(ptr)).data_size() };
+        assert_eq!(handle.live_bytes, counted(small) + counted(large));
+
+        let grown = heap_realloc(&mut handle, small, 2000);
+        assert_eq!(handle.live_bytes, counted(grown) + counted(large));
+        assert!(heap_shrink_in_place(&mut handle, grown, 10));
+        assert_eq!(handle.live_bytes, counted(grown) + counted(large));
+        let peak = handle.peak_bytes;
+        assert!(peak >= 2000 + (1 << 20));
+
+        heap_free(&mut handle, grown).unwrap();
+        heap_free(&mut handle, large).unwrap();
+        assert_eq!(handle.live_bytes, 0);
+        assert_eq!(handle.peak_bytes, peak);
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn global_high_watermark_outlasts_frees() {
+        let size = 64 * 1024;
+        reset_high_watermark();
+        let ptr = malloc(size);
+        unsafe { free(ptr) };
+        // Other tests' arenas only add to it.
+        assert!(high_watermark() >= size);
+    }
+
+    #[test]
+    fn global_histogram_sees_live_allocations() {
+        let ptrs = [malloc(3000), malloc(3000)];
+        let (smallest, count) = size_class_histogram()[7];
+        assert_eq!(smallest, 2048);
+        // Other tests may hold blocks of the same class.
+        assert!(count >= 2);
+        for ptr in ptrs {
+            unsafe { free(ptr) };
+        }
+    }
+
+    #[test]
+    fn large_mappings_are_released_to_the_last_page() {
+        let page = get_page_size();
+        let size = 40 * page + 123;
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, size);
+        let block = large_block(ptr, handle.large).unwrap();
+        let (start, length) = large_mapping(block);
+        assert_eq!(length % page, 0);
+        assert!(start as usize + length > ptr as usize + size);
+        // The slack after the data is mapped all the same.
+        let last = unsafe { start.add(length - page) };
+        unsafe { last.cast::<u8>().cast_mut().add(page - 1).write(1) };
+
+        heap_free(&mut handle, ptr).unwrap();
+        heap_trim(&mut handle);
+        // Not even the last page is left mapped.
+        assert!(mem_protect(last, page, MMAP_PROT_READ_WRITE).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn batches_share_one_mapping_and_free_one_by_one() {
+        use crate::{alloc_batch, verify_integrity};
+
+        let size = 48;
+        let ptrs = alloc_batch(size, 1000);
+        assert_eq!(ptrs.len(), 1000);
+        let stride = size.next_multiple_of(MIN_ALIGN) + REDZONE + Block::size();
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            assert_eq!(ptr as usize, ptrs[0] as usize + i * stride);
+            unsafe { ptr.cast::<u8>().write_bytes(i as u8, size) };
+        }
+        for (i, &ptr) in ptrs.iter().enumerate().rev() {
+            assert_eq!(unsafe { *ptr.cast::<u8>().add(size - 1) }, i as u8);
+            unsafe { try_free(ptr) }.unwrap();
+        }
+        verify_integrity().unwrap();
+
+        // Blocks that get a mapping of their own come one by one.
+        let large = alloc_batch(1 << 20, 3);
+        assert_eq!(large.len(), 3);
+        for ptr in large {
+            unsafe { try_free(ptr) }.unwrap();
+        }
+        assert!(alloc_batch(size, 0).is_empty());
+        // Counts whose pointers alone would not fit in memory.
+        assert!(alloc_batch(size, 1 << 44).is_empty());
+        assert!(alloc_batch(size, usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn page_aligned_pages_get_a_mapping_of_their_own() {
+        let page = get_page_size();
+        let mut handle = HeapHandle::new();
+        let before = LIVE_MAPS.get();
+        let layout = Layout::from_size_align(2 * page, page).unwrap();
+        let ptr = heap_allocate(&mut handle, layout);
+        assert_eq!(ptr as usize % page, 0);
+        assert!(large_block(ptr, handle.large).is_some());
+        assert!(handle.heap.is_null());
+        assert_eq!(LIVE_MAPS.get(), before + 1);
+        unsafe { ptr.cast::<u8>().write_bytes(0x3c, 2 * page) };
+
+        heap_free(&mut handle, ptr).unwrap();
+        heap_trim(&mut handle);
+        assert_eq!(LIVE_MAPS.get(), before);
+    }
+
+    #[test]
+    fn stale_tracked_pointers_are_refused() {
+        let first = malloc_tracked(40);
+        unsafe { free_tracked(first) }.unwrap();
+        // The freed block is the first fit for the same size.
+        let second = malloc_tracked(40);
+        assert_eq!(second.ptr, first.ptr);
+        assert_ne!(second.generation, first.generation);
+        assert_eq!(unsafe { free_tracked(first) }, Err(FreeError::Stale));
+        assert!(contains(second.ptr));
+        unsafe { free_tracked(second) }.unwrap();
+        assert_eq!(unsafe { free_tracked(second) }, Err(FreeError::DoubleFree));
+    }
+
+    #[test]
+    fn quarantined_blocks_are_not_reused_until_released() {
+        let mut handle = HeapHandle::with_config(AllocatorConfig::new().quarantine(2));
+        let first = heap_alloc(&mut handle, 40);
+        heap_free(&mut handle, first).unwrap();
+        assert_eq!(heap_free(&mut handle, first), Err(FreeError::DoubleFree));
+        let second = heap_alloc(&mut handle, 40);
+        assert_ne!(second, first);
+        let third = heap_alloc(&mut handle, 40);
+        heap_free(&mut handle, second).unwrap();
+        // A third block in quarantine pushes the first one out.
+        heap_free(&mut handle, third).unwrap();
+        assert_eq!(heap_alloc(&mut handle, 40), first);
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn writes_to_quarantined_blocks_are_reported() {
+        let mut handle = HeapHandle::with_config(AllocatorConfig::new().quarantine(1));
+        let stale = heap_alloc(&mut handle, 200_000);
+        let other = heap_alloc(&mut handle, 40);
+        heap_free(&mut handle, stale).unwrap();
+        assert_eq!(unsafe { *stale.cast::<u8>() }, FREED_BYTE);
+        unsafe { stale.cast::<u8>().add(1000).write(7) };
+        assert_eq!(heap_free(&mut handle, other), Err(FreeError::Corrupted(stale)));
+        assert!(handle.large.is_null());
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn interior_pointers_lead_back_to_their_allocation() {
+        for size in [40, 600, 200_000] {
+            let base = malloc(size);
+            let inner = unsafe { base.cast::<u8>().add(7) };
+            assert_eq!(get_allocation_base(inner.cast()), base);
+            let last = unsafe { base.cast::<u8>().add(size - 1) };
+            assert_eq!(get_allocation_base(last.cast()), base);
+            unsafe { free(base) };
+        }
+        let bogus = [0u64; 4];
+        assert!(get_allocation_base(bogus.as_ptr().cast()).is_null());
+
+        let mut handle = HeapHandle::new();
+        let keep = heap_alloc(&mut handle, 40);
+        let freed = heap_alloc(&mut handle, 40);
+        heap_free(&mut handle, freed).unwrap();
+        assert_eq!(heap_allocation_base(&handle, freed), None);
+        let inner = unsafe { keep.cast::<u8>().add(39) }.cast();
+        assert_eq!(heap_allocation_base(&handle, inner), Some(keep));
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn rounded_requests_reuse_each_others_blocks() {
+        let config = AllocatorConfig::new().round_to_powers_of_two(256);
+        let mut handle = HeapHandle::with_config(config);
+        let first = heap_alloc(&mut handle, 40);
+        let _keep = heap_alloc(&mut handle, 16);
+        assert_eq!(heap_usable_size(&handle, first), Some(64));
+        heap_free(&mut handle, first).unwrap();
+        // 50 bytes would not fit the 48-byte block 40 bytes get otherwise.
+        assert_eq!(heap_alloc(&mut handle, 50), first);
+        // Past the cap, sizes only round to multiples of 16.
+        let past = heap_alloc(&mut handle, 300);
+        assert_eq!(heap_usable_size(&handle, past), Some(304));
+        heap_reset(&mut handle);
+
+        let mut handle = HeapHandle::new();
+        let first = heap_alloc(&mut handle, 40);
+        let _keep = heap_alloc(&mut handle, 16);
+        heap_free(&mut handle, first).unwrap();
+        assert_ne!(heap_alloc(&mut handle, 50), first);
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn walks_stop_at_links_leaving_the_heap() {
+        let mut handle = HeapHandle::new();
+        let ptrs: Vec<_> = (0..4).map(|_| heap_alloc(&mut handle, 40)).collect();
+        let heap = handle.heap;
+        assert_eq!(blocks_of(heap).count(), 4);
+        let second = unsafe { block_unshift!(ptrs[1]) };
+        let saved = unsafe { (*second).next };
+        for wild in [0x40 as *const Block, heap.cast(), ptr::dangling(), usize::MAX as *const _] {
+            unsafe { (*second).next = wild };
+            assert_eq!(blocks_of(heap).count(), 2);
+            assert_eq!(parent_heap(ptrs[3], heap), None);
+            assert_eq!(parent_heap(ptrs[1], heap), Some(heap));
+        }
+        unsafe { (*second).next = saved };
+        assert_eq!(get_last_block(heap), unsafe { block_unshift!(ptrs[3]) });
+
+        heap_free(&mut handle, ptrs[2]).unwrap();
+        let freed = unsafe { block_unshift!(ptrs[2]) };
+        unsafe { (*freed).set_free_next(0x40 as *mut Block) };
+        // Too big for the freed block, but from the same bin.
+        let size = unsafe { (*freed).data_size() } + 8;
+        assert_eq!(bin_index(size), bin_index(unsafe { (*freed).data_size() }));
+        assert_eq!(get_free_block(size, heap), None);
+        unsafe { (*freed).set_free_next(ptr::null_mut()) };
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn last_block_follows_the_tail() {
+        let mut handle = HeapHandle::new();
+        let check = |handle: &HeapHandle| {
+            for heap in heap_list(handle) {
+                let tail = blocks_of(heap).last().unwrap_or(ptr::null_mut());
+                assert_eq!(unsafe { (*heap).last }, tail);
+            }
+        };
+        let mut seed = 0x2545_f491_u32;
+        let mut ptrs = Vec::new();
+        for i in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let size = seed as usize % 600 + 1;
+            match i % 5 {
+                0 | 1 => ptrs.push(heap_alloc(&mut handle, size)),
+                2 if !ptrs.is_empty() => {
+                    let ptr = ptrs.swap_remove(seed as usize % ptrs.len());
+                    heap_free(&mut handle, ptr).unwrap();
+                }
+                3 if !ptrs.is_empty() => {
+                    let j = seed as usize % ptrs.len();
+                    ptrs[j] = heap_realloc(&mut handle, ptrs[j], size);
+                }
+                _ => {
+                    heap_defragment(&mut handle);
+                }
+            }
+            check(&handle);
+        }
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+            check(&handle);
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn idle_bytes_follow_the_empty_heaps() {
+        let config = AllocatorConfig::new().trim_threshold(64 * PAGE_SIZE);
+        let mut handle = HeapHandle::with_config(config);
+        let check = |handle: &HeapHandle| {
+            let idle: usize = heap_list(handle)
+                .into_iter()
+                .filter(|&heap| unsafe { (*heap).free_size == (*heap).total_size - Heap::size() })
+                .map(|heap| unsafe { (*heap).total_size })
+                .sum();
+            assert_eq!(handle.idle_bytes, idle);
+        };
+        let mut seed = 0x9e37_79b9_u32;
+        let mut ptrs = Vec::new();
+        for i in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let size = seed as usize % 3000 + 1;
+            match i % 7 {
+                0 | 1 => ptrs.push(heap_alloc(&mut handle, size)),
+                2 | 3 if !ptrs.is_empty() => {
+                    let ptr = ptrs.swap_remove(seed as usize % ptrs.len());
+                    heap_free(&mut handle, ptr).unwrap();
+                }
+                4 if !ptrs.is_empty() => {
+                    let j = seed as usize % ptrs.len();
+                    ptrs[j] = heap_realloc(&mut handle, ptrs[j], size);
+                }
+                5 => {
+                    heap_reserve(&mut handle, size, 2);
+                }
+                _ => {
+                    heap_trim(&mut handle);
+                }
+            }
+            check(&handle);
+        }
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+            check(&handle);
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn sequential_append_benchmark() {
+        // One heap big enough that every block is appended to the same chain.
+        let config = AllocatorConfig::new().tiny_heap_size(64 << 20);
+        let mut handle = HeapHandle::with_config(config);
+        let start = Instant::now();
+        for _ in 0..200_000 {
+            assert!(!heap_alloc(&mut handle, 64).is_null());
+        }
+        println!("200000 appends: {:?}", start.elapsed());
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn sized_frees_check_the_size_in_debug_builds() {
+        let ptr = malloc(100);
+        if cfg!(debug_assertions) {
+            assert_eq!(unsafe { free_sized(ptr, 4096) }, Err(FreeError::WrongSize));
+            assert!(contains(ptr));
+        }
+        assert_eq!(unsafe { free_sized(ptr, 100) }, Ok(()));
+        assert!(unsafe { free_sized(ptr, 100) }.is_err());
+    }
+
+    #[test]
+    fn large_realloc_keeps_contents() {
+        let _serial = serial();
+        let mut handle = HeapHandle::new();
+        let sizes = [200_000, 300_000, 250_000];
+        let ptrs = sizes.map(|size| heap_alloc(&mut handle, size) as *mut u8);
+        for (i, ptr) in ptrs.iter().enumerate() {
+            unsafe { ptr.write_bytes(i as u8 + 1, sizes[i]) };
+        }
+        let maps = LIVE_MAPS.get();
+        let grown = heap_realloc(&mut handle, ptrs[1].cast(), 8 << 20) as *mut u8;
+        assert!(!grown.is_null());
+        // The OS resizes the mapping instead of the data being copied over.
+        #[cfg(target_os = "linux")]
+        assert_eq!(LIVE_MAPS.get(), maps);
+        #[cfg(not(target_os = "linux"))]
+        let _ = maps;
+        let data = unsafe { slice::from_raw_parts_mut(grown, 8 << 20) };
+        assert!(data[..sizes[1]].iter().all(|&byte| byte == 2));
+        data[sizes[1]..].fill(0x77);
+
+        // The large list still links every block both ways.
+        let mut block = handle.large;
+        let mut previous = ptr::null();
+        let mut count = 0;
+        while !block.is_null() {
+            assert_eq!(unsafe { (*block).previous }, previous);
+            previous = block;
+            block = unsafe { (*block).next as *mut Block };
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        for ptr in [ptrs[0], grown, ptrs[2]] {
+            heap_free(&mut handle, ptr.cast()).unwrap();
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn realloc_shrinks_in_place() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 512);
+        let _b = heap_alloc(&mut handle, 16);
+        assert_eq!(heap_realloc(&mut handle, a, 100), a);
+        let block = unsafe { block_unshift!(a) };
+        unsafe {
+            assert_eq!((*block).data_size(), 112 + REDZONE);
+            assert!((*(*block).next).is_free());
+        }
+    }
+
+    #[test]
+    fn realloc_edge_cases() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_realloc(&mut handle, std::ptr::null_mut(), 24);
+        assert!(!ptr.is_null());
+        assert!(heap_realloc(&mut handle, ptr, 0).is_null());
+
+        let large = heap_alloc(&mut handle, 200_000);
+        assert_eq!(heap_realloc(&mut handle, large, 150_000), large);
+        let larger = heap_realloc(&mut handle, large, 300_000);
+        unsafe { (larger as *mut u8).add(299_999).write(1) };
+        heap_free(&mut handle, larger).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn bytes_are_counted_by_tag() {
+        use crate::{bytes_by_tag, malloc_tagged};
+
+        const PARSER: u32 = 0xa1;
+        const RENDER: u32 = 0xa2;
+        let parser: Vec<_> = (0..3).map(|_| malloc_tagged(100, PARSER)).collect();
+        let render = [1000, 1000, 300_000].map(|size| malloc_tagged(size, RENDER));
+        let untagged = malloc(100);
+        unsafe {
+            free(parser[0]);
+            free(render[0]);
+        }
+        // Moving to a new block keeps the tag.
+        let moved = unsafe { realloc(parser[1], 5000) };
+        assert_ne!(moved, parser[1]);
+
+        let bytes = bytes_by_tag();
+        let usable = |ptrs: &[*mut c_void]| ptrs.iter().map(|&p| malloc_usable_size(p)).sum();
+        assert_eq!(bytes.get(&PARSER), Some(&usable(&[moved, parser[2]])));
+        assert_eq!(bytes.get(&RENDER), Some(&usable(&render[1..])));
+        assert!(bytes[&0] >= malloc_usable_size(untagged));
+        unsafe {
+            for ptr in [moved, parser[2], render[1], render[2], untagged] {
+                free(ptr);
+            }
+        }
+        assert!(!bytes_by_tag().contains_key(&PARSER));
+    }
+
+    #[test]
+    fn calloc_zeroes_reused_memory() {
+        let _serial = serial();
+        unsafe {
+            let ptr = malloc(48) as *mut u8;
+            ptr.write_bytes(0xff, 48);
+            free(ptr as *mut c_void);
+            let zeroed = calloc(6, 8) as *mut u8;
+            assert!((0..48).all(|i| zeroed.add(i).read() == 0));
+            free(zeroed as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn large_calloc_is_zeroed_fresh_and_reused() {
+        let mut handle = HeapHandle::new();
+        let size = 1 << 20;
+        for fresh in [true, false] {
+            let ptr = heap_calloc(&mut handle, size) as *mut u8;
+            assert_eq!(handle.fresh, fresh);
+            let bytes = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+            assert!(bytes.iter().all(|&b| b == 0));
+            // Left dirty for the cached mapping's next user.
+            bytes.fill(0xee);
+            heap_free(&mut handle, ptr as *const c_void).unwrap();
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn large_calloc_throughput() {
+        let mut handle = HeapHandle::new();
+        let size = 32 << 20;
+        for round in ["fresh", "reused"] {
+            let start = Instant::now();
+            let ptr = heap_calloc(&mut handle, size);
+            println!("{round}: {:?} for {size} zeroed bytes", start.elapsed());
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn sizes_near_usize_max_are_refused() {
+        assert_eq!(align(16, usize::MAX - 3), None);
+        assert_eq!(align(16, usize::MAX - 15), Some(usize::MAX - 15));
+        let mut handle = HeapHandle::new();
+        for size in [usize::MAX, usize::MAX - 3, usize::MAX - Block::size()] {
+            assert!(heap_alloc(&mut handle, size).is_null());
+        }
+        let layout = Layout::from_size_align(isize::MAX as usize - 4095, 4096).unwrap();
+        assert!(heap_allocate(&mut handle, layout).is_null());
+        let ptr = heap_alloc(&mut handle, 32);
+        unsafe { (ptr as *mut u8).write_bytes(0x77, 32) };
+        assert!(heap_realloc(&mut handle, ptr, usize::MAX - 3).is_null());
+        assert_eq!(heap_usable_size(&handle, ptr), Some(32));
+        assert!((0..32).all(|i| unsafe { (ptr as *const u8).add(i).read() } == 0x77));
+        heap_free(&mut handle, ptr).unwrap();
+    }
+
+    #[test]
+    fn calloc_overflow_returns_null() {
+        let _serial = serial();
+        assert!(calloc(usize::MAX, 2).is_null());
+        assert!(calloc(2, usize::MAX / 2 + 1).is_null());
+    }
+
+    #[test]
+    fn reallocarray_overflow_keeps_the_allocation() {
+        let _serial = serial();
+        let ptr = malloc(32) as *mut u8;
+        unsafe {
+            ptr.write_bytes(0x3c, 32);
+            assert!(reallocarray(ptr as *mut c_void, usize::MAX, 2).is_null());
+            assert!((0..32).all(|i| ptr.add(i).read() == 0x3c));
+            let grown = reallocarray(ptr as *mut c_void, 16, 8) as *mut u8;
+            assert!(malloc_usable_size(grown as *const c_void) >= 128);
+            assert!((0..32).all(|i| grown.add(i).read() == 0x3c));
+            free(grown as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn heap_groups_split_at_the_block_sizes() {
+        let config = AllocatorConfig::new();
+        let (tiny, small) = (config.tiny_block_size(), config.small_block_size());
+        let table = [
+            (tiny, HeapGroup::Tiny(0)),
+            (tiny + 1, HeapGroup::Small(0)),
+            (small, HeapGroup::Small(0)),
+            (small + 1, HeapGroup::Large(0)),
+        ];
+        for (size, group) in table {
+            assert_eq!(discriminant(&HeapGroup::new(size, &config)), discriminant(&group));
+            let mut handle = HeapHandle::new();
+            assert!(!alloc_block(&mut handle, size).is_null());
+            assert_eq!(discriminant(unsafe { &(*handle.heap).group }), discriminant(&group));
+            heap_reset(&mut handle);
+        }
+    }
+
+    #[test]
+    fn first_allocation_maps_one_heap() {
+        let mappings = |handle: &HeapHandle| {
+            let mut stats = HeapStats::default();
+            heap_stats(handle, &mut stats);
+            stats.total().mappings
+        };
+        // Tiny, small, and exact-size heaps.
+        for size in [16, 500, 5000] {
+            let mut handle = HeapHandle::new();
+            let first = heap_alloc(&mut handle, size);
+            assert_eq!(mappings(&handle), 1);
+            assert!(unsafe { (*handle.heap).next }.is_null());
+            if size < 5000 {
+                // Another block of the same group fits in the same heap.
+                let second = heap_alloc(&mut handle, size);
+                assert_eq!(mappings(&handle), 1);
+                heap_free(&mut handle, second).unwrap();
+            }
+            heap_free(&mut handle, first).unwrap();
+        }
+    }
+
+    #[test]
+    fn exact_fit_returns_data_pointer() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 32);
+        let _b = heap_alloc(&mut handle, 16);
+        let free_size = unsafe { (*handle.heap).free_size };
+        heap_free(&mut handle, a).unwrap();
+        let again = heap_alloc(&mut handle, 32);
+        assert_eq!(again, a);
+        let block = unsafe { block_unshift!(again) };
+        assert!(!unsafe { (*block).is_free() });
+        assert_eq!(unsafe { (*handle.heap).free_size }, free_size);
+    }
+
+    #[test]
+    fn coalescing_restores_free_size() {
+        let mut handle = HeapHandle::new();
+        let baseline = Heap::new(16, &AllocatorConfig::new()).free_size;
+        let orders: [[usize; 5]; 3] = [[0, 1, 2, 3, 4], [4, 3, 2, 1, 0], [1, 3, 0, 4, 2]];
+        for order in orders {
+            let sizes = [16, 40, 24, 100, 8];
+            let ptrs: Vec<_> = sizes.map(|size| heap_alloc(&mut handle, size)).into();
+            for i in order {
+                heap_free(&mut handle, ptrs[i]).unwrap();
+            }
+            assert_eq!(unsafe { (*handle.heap).block_count }, 1);
+            assert_eq!(unsafe { (*handle.heap).free_size }, baseline);
+        }
+    }
+
+    #[test]
+    fn healthy_heaps_pass_verification() {
+        let mut handle = HeapHandle::new();
+        assert_eq!(heap_verify(&handle), Ok(()));
+        let mut ptrs: Vec<_> =
+            [16, 100, 600, 3000, 48, 200_000, 900].map(|size| heap_alloc(&mut handle, size)).into();
+        assert_eq!(heap_verify(&handle), Ok(()));
+        for i in [1, 4, 5] {
+            heap_free(&mut handle, ptrs[i]).unwrap();
+            assert_eq!(heap_verify(&handle), Ok(()));
+        }
+        ptrs[0] = heap_realloc(&mut handle, ptrs[0], 90);
+        ptrs[2] = heap_realloc(&mut handle, ptrs[2], 100);
+        assert_eq!(heap_verify(&handle), Ok(()));
+        for i in [0, 2, 3, 6] {
+            heap_free(&mut handle, ptrs[i]).unwrap();
+        }
+        assert_eq!(heap_verify(&handle), Ok(()));
+        heap_trim(&mut handle);
+
+        #[cfg(feature = "std")]
+        assert_eq!(crate::verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn corrupted_heaps_fail_verification() {
+        let mut handle = HeapHandle::new();
+        let [a, b, _c] = [100, 100, 100].map(|size| heap_alloc(&mut handle, size));
+        let heap = handle.heap as *const Heap;
+        let block = unsafe { block_unshift!(b) };
+        unsafe {
+            let previous = (*block).previous;
+            (*block).previous = ptr::null();
+            let err = heap_verify(&handle).unwrap_err();
+            assert_eq!(err, Corruption::BrokenLink(heap, block));
+            assert!(err.to_string().contains("linked inconsistently"));
+            (*block).previous = previous;
+
+            (*handle.heap).free_size += 16;
+            assert!(matches!(heap_verify(&handle), Err(Corruption::FreeSize(..))));
+            (*handle.heap).free_size -= 16;
+            (*handle.heap).block_count -= 1;
+            assert!(matches!(heap_verify(&handle), Err(Corruption::BlockCount(..))));
+            (*handle.heap).block_count += 1;
+        }
+        heap_free(&mut handle, a).unwrap();
+        let first = unsafe { block_unshift!(a) };
+        unsafe {
+            (*first).set_data_size((*first).data_size() + 16);
+            assert!(matches!(heap_verify(&handle), Err(Corruption::BrokenLink(..))));
+            (*first).set_data_size((*first).data_size() - 16);
+            (*handle.heap).bins = [ptr::null_mut(); BIN_COUNT];
+            assert_eq!(heap_verify(&handle), Err(Corruption::Unlisted(heap)));
+        }
+    }
+
+    // With `ffi` the panic message itself is allocated from the corrupted
+    // arena.
+    #[cfg(all(feature = "std", debug_assertions, not(feature = "ffi")))]
+    #[test]
+    fn debug_builds_check_the_heaps_on_free() {
+        use std::panic;
+
+        let kept = malloc(64);
+        let ptr = malloc(64);
+        let heap = arena::with_arena(|handle| parent_heap(ptr, handle.heap)).unwrap();
+        unsafe { (*heap).free_size += 16 };
+        let caught = panic::catch_unwind(|| unsafe { try_free(ptr) });
+        // Set the arena right before anything else uses it.
+        unsafe { (*heap).free_size -= 16 };
+        let message = *caught.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("free bytes"), "{message}");
+        unsafe { free(kept) };
+    }
+
+    #[test]
+    fn empty_heaps_are_skipped_when_freeing() {
+        let mut handle = HeapHandle::new();
+        let tiny = heap_alloc(&mut handle, 16);
+        // A new small heap, its memory past the header never written by a
+        // block: fill it with garbage to make any walk of it go astray.
+        let empty = create_heap(600, &handle.config).unwrap();
+        unsafe {
+            let body = heap_shift!(empty);
+            body.write_bytes(0xAA, (*empty).total_size - Heap::size());
+            link_heap(&mut handle, empty);
+            let stray = body.add(64) as *mut c_void;
+            assert_eq!(heap_free(&mut handle, stray), Err(FreeError::InvalidPointer));
+        }
+        heap_free(&mut handle, tiny).unwrap();
+        heap_trim(&mut handle);
+        assert!(handle.heap.is_null());
+    }
+
+    // Redzones move these sizes out of the size classes the test relies on.
+    #[cfg(not(feature = "redzone"))]
+    #[test]
+    fn split_accounts_for_free_size() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 128);
+        let _b = heap_alloc(&mut handle, 16);
+        let before = unsafe { (*handle.heap).free_size };
+        heap_free(&mut handle, a).unwrap();
+        assert_eq!(unsafe { (*handle.heap).free_size }, before + 128 + Block::size());
+
+        let c = heap_alloc(&mut handle, 32);
+        assert_eq!(c, a);
+        // The tail keeps its header, so only the 32 handed out is gone.
+        assert_eq!(unsafe { (*handle.heap).free_size }, before + 128 - 32);
+        let tail = unsafe { (*(block_unshift!(c))).next };
+        assert_eq!(unsafe { (*tail).data_size() }, 128 - 32 - Block::size());
+    }
+
+    #[test]
+    fn large_allocations_are_tracked() {
+        let mut handle = HeapHandle::new();
+        let sizes = [200_000, 300_000, 150_000, 500_000];
+        let ptrs: Vec<_> = sizes.iter().map(|s| heap_alloc(&mut handle, *s)).collect();
+        for (ptr, size) in ptrs.iter().zip(sizes) {
+            unsafe { (*ptr as *mut u8).add(size - 1).write(0xaa) };
+        }
+        for i in [2, 0, 3, 1] {
+            heap_free(&mut handle, ptrs[i]).unwrap();
+        }
+        assert!(handle.large.is_null());
+        assert!(handle.heap.is_null());
+    }
+
+    #[test]
+    fn block_flags_pack_into_the_size() {
+        let mut block = Block::new(64);
+        assert!(!block.is_free() && !block.is_guarded());
+        block.set_free(true);
+        assert!(block.is_free() && !block.is_guarded());
+        block.set_guarded(true);
+        assert!(block.is_free() && block.is_guarded());
+        block.set_data_size(4096);
+        assert!(block.is_free() && block.is_guarded());
+        assert_eq!(block.data_size(), 4096);
+        block.set_free(false);
+        assert!(!block.is_free() && block.is_guarded());
+        block.set_guarded(false);
+        assert_eq!(block.size_flags, 4096);
+
+        // The stamps share a word with the free-list link, so the header is
+        // four words instead of six.
+        block.set_stamps(7, 0xa1);
+        assert_eq!((block.generation(), block.tag()), (7, 0xa1));
+        block.set_free_next(ptr::null_mut());
+        assert!(block.free_next().is_null());
+        let site = if cfg!(feature = "leak-check") { 16 } else { 0 };
+        assert_eq!(Block::size(), 32 + site);
+    }
+
+    #[test]
+    fn large_headers_start_out_clean() {
+        let mut handle = HeapHandle::new();
+        let (ptrs, spacers) = apart(&mut handle, [200_000, 500_000]);
+        let fresh = ptrs[0];
+        heap_free(&mut handle, fresh).unwrap();
+        heap_free(&mut handle, ptrs[1]).unwrap();
+        // Both sit in the cache now, linked to each other and marked free.
+        let reused = heap_alloc(&mut handle, 200_000);
+        assert_eq!(reused, fresh);
+        let block = unsafe { *(block_unshift!(reused)) };
+        assert!(!block.is_free());
+        assert!(!block.is_guarded());
+        assert!(ptr::eq(block.next, unsafe { block_unshift!(spacers[1]) }));
+        assert!(block.previous.is_null());
+        assert_eq!(block.tag(), 0);
+        for ptr in spacers.into_iter().chain([reused]) {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        heap_trim(&mut handle);
+    }
+
+    #[test]
+    fn large_double_free() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, 200_000);
+        let _keep = heap_alloc(&mut handle, 200_000);
+        heap_free(&mut handle, ptr).unwrap();
+        assert_eq!(heap_free(&mut handle, ptr), Err(FreeError::InvalidPointer));
+    }
+
+    #[test]
+    fn try_free_reports_bad_pointers() {
+        let _serial = serial();
+        let mut bogus = [0u64; 8];
+        let ptr = unsafe { bogus.as_mut_ptr().add(4) } as *mut c_void;
+        assert_eq!(unsafe { try_free(ptr) }, Err(FreeError::InvalidPointer));
+        assert_eq!(unsafe { try_free(std::ptr::null_mut()) }, Err(FreeError::InvalidPointer));
+    }
+
+    #[test]
+    fn try_free_reports_double_free() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 16);
+        let _b = heap_alloc(&mut handle, 16);
+        heap_free(&mut handle, a).unwrap();
+        assert_eq!(heap_free(&mut handle, a), Err(FreeError::DoubleFree));
+    }
+
+    #[test]
+    fn try_realloc_reports_bad_pointers() {
+        let _serial = serial();
+        let mut bogus = [0u64; 8];
+        let ptr = unsafe { bogus.as_mut_ptr().add(4) } as *mut c_void;
+        for size in [64, 0] {
+            assert_eq!(unsafe { try_realloc(ptr, size) }, Err(FreeError::InvalidPointer));
+        }
+
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 16);
+        let _b = heap_alloc(&mut handle, 16);
+        assert_eq!(heap_try_realloc(&mut handle, a, 8), Ok(a));
+        heap_free(&mut handle, a).unwrap();
+        assert_eq!(heap_try_realloc(&mut handle, a, 0), Err(FreeError::DoubleFree));
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn aligned_alloc_honours_alignment() {
+        let _serial = serial();
+        for align in [16, 64, 512, 4096] {
+            let ptr = aligned_alloc(align, 100);
+            assert_eq!(ptr as usize % align, 0);
+            unsafe {
+                (ptr as *mut u8).write_bytes(0x5a, 100);
+                free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_realloc_keeps_the_alignment() {
+        let ptr = aligned_alloc(64, 100) as *mut u8;
+        unsafe {
+            for i in 0..100 {
+                ptr.add(i).write(i as u8);
+            }
+            let grown = aligned_realloc(ptr as *mut c_void, 64, 5000) as *mut u8;
+            assert_eq!(grown as usize % 64, 0);
+            assert!((0..100).all(|i| grown.add(i).read() == i as u8));
+            grown.add(100).write_bytes(0x5a, 4900);
+
+            let shrunk = aligned_realloc(grown as *mut c_void, 64, 50) as *mut u8;
+            assert_eq!(shrunk, grown);
+            let large = aligned_realloc(shrunk as *mut c_void, 4096, 300_000) as *mut u8;
+            assert_eq!(large as usize % 4096, 0);
+            assert!((0..50).all(|i| large.add(i).read() == i as u8));
+
+            assert!(aligned_realloc(large as *mut c_void, 24, 100).is_null());
+            assert!(aligned_realloc(large as *mut c_void, 4096, 0).is_null());
+            assert!(!contains(large as *const c_void));
+        }
+    }
+
+    #[test]
+    fn aligned_alloc_rejects_bad_alignment() {
+        let _serial = serial();
+        assert!(aligned_alloc(24, 100).is_null());
+        assert!(aligned_alloc(0, 100).is_null());
+    }
+
+    #[test]
+    fn zero_sized_aligned_blocks_free_at_any_offset() {
+        let mut handle = HeapHandle::new();
+        let layout = Layout::from_size_align(0, 64).unwrap();
+        // Each spacer moves the next block 16 bytes further off a 64-byte
+        // boundary, so one of them has its data a full alignment in.
+        let mut ptrs = Vec::new();
+        for _ in 0..4 {
+            let ptr = heap_allocate(&mut handle, layout);
+            assert_eq!(ptr as usize % 64, 0);
+            ptrs.push(ptr);
+            ptrs.push(heap_alloc(&mut handle, 8));
+        }
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn aligned_pointer_round_trips_through_free() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_allocate(&mut handle, Layout::from_size_align(40, 64).unwrap());
+        let _keep = heap_alloc(&mut handle, 16);
+        assert_eq!(ptr as usize % 64, 0);
+        heap_free(&mut handle, ptr).unwrap();
+        // Poisoning wipes the back-offset, so the pointer is not recognised.
+        let expected = if cfg!(feature = "poison") {
+            FreeError::InvalidPointer
+        } else {
+            FreeError::DoubleFree
+        };
+        assert_eq!(heap_free(&mut handle, ptr), Err(expected));
+    }
+
+    #[test]
+    fn allocate_honours_any_layout() {
+        let _serial = serial();
+        let layouts = [(0, 1), (0, 64), (1, 1), (24, 8), (100, 16), (100, 256), (300_000, 4096)];
+        for (size, align) in layouts {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = allocate(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            assert!(malloc_usable_size(ptr) >= size);
+            unsafe {
+                (ptr as *mut u8).write_bytes(0x5a, size);
+                deallocate(ptr, layout).unwrap();
+                assert!(deallocate(ptr, layout).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn zero_sized_layouts_are_distinct() {
+        let _serial = serial();
+        let layout = Layout::from_size_align(0, 128).unwrap();
+        let a = allocate(layout);
+        let b = allocate(layout);
+        assert_ne!(a, b);
+        unsafe {
+            deallocate(a, layout).unwrap();
+            deallocate(b, layout).unwrap();
+        }
+    }
+
+    #[test]
+    fn large_aligned_blocks_align_their_header() {
+        let mut handle = HeapHandle::new();
+        let before = LIVE_MAPS.get();
+        for align in [4096, 64 * 1024] {
+            let layout = Layout::from_size_align(300_000, align).unwrap();
+            let ptr = heap_allocate(&mut handle, layout);
+            assert_eq!(ptr as usize % align, 0);
+            // No back-offset: the pointer is the block's own data.
+            assert_eq!(ptr, unsafe { block_shift!(handle.large) }.cast());
+            assert!(heap_usable_size(&handle, ptr).unwrap() >= 300_000);
+            unsafe { (ptr as *mut u8).write_bytes(0x5a, 300_000) };
+            heap_free(&mut handle, ptr).unwrap();
+
+            // The cached mapping keeps its lead when reused.
+            let reused = heap_alloc(&mut handle, 300_000);
+            assert_eq!(reused, ptr);
+            heap_free(&mut handle, reused).unwrap();
+            heap_trim(&mut handle);
+            assert_eq!(LIVE_MAPS.get(), before);
+        }
+    }
+
+    #[test]
+    fn usable_size_reports_aligned_capacity() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, 10);
+        assert_eq!(heap_usable_size(&handle, ptr), Some(16));
+        let ptr = heap_realloc(&mut handle, ptr, 100);
+        assert_eq!(heap_usable_size(&handle, ptr), Some(112));
+        let ptr = heap_realloc(&mut handle, ptr, 16);
+        assert_eq!(heap_usable_size(&handle, ptr), Some(16));
+
+        let large = heap_alloc(&mut handle, 200_001);
+        assert_eq!(heap_usable_size(&handle, large), Some(200_016));
+        heap_free(&mut handle, large).unwrap();
+        assert_eq!(heap_usable_size(&handle, large), None);
+    }
+
+    #[test]
+    fn contains_only_live_allocations() {
+        let _serial = serial();
+        // With `ffi` a box comes from this allocator too.
+        if !cfg!(feature = "ffi") {
+            let foreign = Box::new(0u64);
+            assert!(!contains(&*foreign as *const u64 as *const c_void));
+        }
+        assert!(!contains(std::ptr::null()));
+        for size in [24, 5000, 300_000] {
+            let ptr = malloc(size);
+            assert!(contains(ptr));
+            assert!(!contains(unsafe { (ptr as *const u8).add(16) } as *const c_void));
+            unsafe { free(ptr) };
+            assert!(!contains(ptr));
+        }
+    }
+
+    #[test]
+    fn back_offset_reads_stay_inside_the_mapping() {
+        // A heap and a large block each right after an inaccessible page, so
+        // reading the word before either start faults.
+        let page = get_page_size();
+        let header = Heap::new(16, &AllocatorConfig::new());
+        let heap_length = header.total_size;
+        let length = page + heap_length + 2 * page;
+        let region = mem_map(length, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as usize;
+        let heap = (region + page) as *mut Heap;
+        let large = (region + page + heap_length + page) as *mut Block;
+        unsafe {
+            mem_protect(region as *const c_void, page, MMAP_PROT_NONE).unwrap();
+            mem_protect((heap as usize + heap_length) as *const c_void, page, MMAP_PROT_NONE)
+                .unwrap();
+            heap.write(header);
+        }
+        let mut handle = HeapHandle::new();
+        link_heap(&mut handle, heap);
+        track_large(&mut handle, large, page - Block::size(), 0, false);
+        let ptr = heap_alloc(&mut handle, 16);
+
+        for start in [heap as usize, large as usize] {
+            for offset in 1..2 * mem::size_of::<usize>() {
+                assert!(!owns(&handle, (start + offset) as *const c_void));
+            }
+        }
+        assert!(owns(&handle, ptr));
+        assert!(!contains((heap as usize + 1) as *const c_void));
+        handle.large = ptr::null_mut();
+        unlink_heap(heap, &mut handle);
+        mem_unmap(region as *const c_void, length).unwrap();
+    }
+
+    #[test]
+    fn usable_size_of_null_is_zero() {
+        let _serial = serial();
+        assert_eq!(malloc_usable_size(std::ptr::null()), 0);
+        let ptr = aligned_alloc(64, 48);
+        assert!(malloc_usable_size(ptr) >= 48);
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn best_fit_picks_the_tightest_block() {
+        let _serial = serial();
+        let mut handle = HeapHandle::new();
+        let loose = heap_alloc(&mut handle, 96);
+        let _a = heap_alloc(&mut handle, 16);
+        let tight = heap_alloc(&mut handle, 64);
+        let _b = heap_alloc(&mut handle, 16);
+        // Both land in the same bin, with the looser block on top.
+        heap_free(&mut handle, tight).unwrap();
+        heap_free(&mut handle, loose).unwrap();
+
+        let ptr = heap_alloc(&mut handle, 64);
+        assert_eq!(ptr, loose);
+        heap_free(&mut handle, ptr).unwrap();
+
+        set_best_fit(true);
+        let ptr = heap_alloc(&mut handle, 64);
+        set_best_fit(false);
+        assert_eq!(ptr, tight);
+    }
+
+    #[test]
+    fn free_blocks_are_reused_lifo() {
+        let mut handle = HeapHandle::new();
+        let ptrs: Vec<_> = (0..6).map(|_| heap_alloc(&mut handle, 24)).collect();
+        heap_free(&mut handle, ptrs[1]).unwrap();
+        heap_free(&mut handle, ptrs[3]).unwrap();
+        heap_free(&mut handle, ptrs[5]).unwrap();
+        assert_eq!(heap_alloc(&mut handle, 24), ptrs[5]);
+        assert_eq!(heap_alloc(&mut handle, 24), ptrs[3]);
+        assert_eq!(heap_alloc(&mut handle, 24), ptrs[1]);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn free_list_lookup_with_many_live_blocks() {
+        let mut handle = HeapHandle::new();
+        let live: Vec<_> = (0..50_000).map(|_| heap_alloc(&mut handle, 16)).collect();
+        for ptr in live.iter().step_by(64) {
+            heap_free(&mut handle, *ptr).unwrap();
+        }
+        let start = Instant::now();
+        for _ in 0..10_000 {
+            let ptr = heap_alloc(&mut handle, 16);
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        println!("10k malloc/free pairs over 50k live blocks: {:?}", start.elapsed());
+
+        // The same lookups through the bins and through a walk of every
+        // block, as finding a fit took before the free lists.
+        let heaps = heap_list(&handle);
+        let size = unsafe { (*block_unshift!(live[1])).data_size() };
+        let lookups = |find: &dyn Fn(*mut Heap) -> Option<*mut Block>| {
+            let start = Instant::now();
+            for &heap in heaps.iter().cycle().take(100_000) {
+                std::hint::black_box(find(heap));
+            }
+            start.elapsed()
+        };
+        let binned = lookups(&|heap| get_free_block(size, heap));
+        let fits =
+            |block: &*mut Block| unsafe { (**block).is_free() && (**block).data_size() >= size };
+        let scanned = lookups(&|heap| blocks_of(heap).find(fits));
+        println!("100k fit lookups over 50k live blocks: {binned:?} binned, {scanned:?} scanned");
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn shifts_step_over_the_headers() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, 64);
+        let heap = handle.heap;
+        unsafe {
+            let block = block_unshift!(ptr);
+            assert_eq!(ptr as usize - block as usize, mem::size_of::<Block>());
+            assert_eq!(block_shift!(block), ptr.cast::<u8>());
+
+            // The first block of a heap sits right after its header.
+            let first = heap_shift!(heap);
+            assert_eq!(first as usize - heap as usize, mem::size_of::<Heap>());
+            assert_eq!(first.cast::<Block>(), block);
+            assert_eq!(heap_unshift!(first), heap);
+        }
+        heap_free(&mut handle, ptr).unwrap();
+    }
+
+    #[test]
+    fn bin_index_boundaries() {
+        assert_eq!(bin_index(16), 0);
+        assert_eq!(bin_index(31), 0);
+        assert_eq!(bin_index(32), 1);
+        assert_eq!(bin_index(1024), 6);
+        assert_eq!(bin_index(usize::MAX), 15);
+    }
+
+    #[cfg(not(feature = "redzone"))]
+    #[test]
+    fn reuse_stays_within_size_class() {
+        let mut handle = HeapHandle::new();
+        let mut freed = Vec::new();
+        for size in [16, 48, 96, 16, 48, 96] {
+            freed.push(heap_alloc(&mut handle, size));
+            heap_alloc(&mut handle, 8);
+        }
+        for ptr in &freed {
+            heap_free(&mut handle, *ptr).unwrap();
+        }
+        let heap = handle.heap;
+        for (size, bin) in [(16, 0), (48, 1), (96, 2)] {
+            let mut block = unsafe { (*heap).bins[bin] };
+            while !block.is_null() {
+                assert_eq!(unsafe { (*block).data_size() }, size);
+                block = unsafe { (*block).free_next() };
+            }
+        }
+        // A plain LIFO list would hand out the 96 byte block freed last.
+        assert_eq!(heap_alloc(&mut handle, 48), freed[4]);
+        assert_eq!(heap_alloc(&mut handle, 16), freed[3]);
+        assert_eq!(heap_alloc(&mut handle, 96), freed[5]);
+        assert_eq!(heap_alloc(&mut handle, 48), freed[1]);
+    }
+
+    #[test]
+    fn freed_large_region_is_reused() {
+        let mut handle = HeapHandle::new();
+        let first = heap_alloc(&mut handle, 1 << 20);
+        heap_free(&mut handle, first).unwrap();
+        assert_eq!(handle.large_cache_len, 1);
+        let second = heap_alloc(&mut handle, 1 << 20);
+        assert_eq!(second, first);
+        assert!(handle.large_cache.is_null());
+
+        // Too small to justify handing out a whole megabyte.
+        heap_free(&mut handle, second).unwrap();
+        let small = heap_alloc(&mut handle, 200_000);
+        assert_ne!(small, first);
+        heap_free(&mut handle, small).unwrap();
+    }
+
+    #[test]
+    fn large_cache_evicts_oldest() {
+        let mut handle = HeapHandle::new();
+        let sizes = (0..LARGE_CACHE_ENTRIES + 2).map(|i| 200_000 + i * 4096);
+        let (ptrs, spacers) = apart(&mut handle, sizes);
+        for ptr in &ptrs {
+            heap_free(&mut handle, *ptr).unwrap();
+        }
+        assert_eq!(handle.large_cache_len, LARGE_CACHE_ENTRIES);
+        let mut block = handle.large_cache;
+        let mut kept = Vec::new();
+        while !block.is_null() {
+            kept.push(unsafe { block_shift!(block) }.cast());
+            block = unsafe { (*block).next as *mut Block };
+        }
+        assert!(!kept.contains(&ptrs[0]));
+        assert!(!kept.contains(&ptrs[1]));
+        assert_eq!(kept[0], ptrs[LARGE_CACHE_ENTRIES + 1]);
+        for ptr in spacers {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        heap_trim(&mut handle);
+    }
+
+    /// Large blocks of `sizes` carved out of one mapping, each followed by a
+    /// page-long spacer block that stays allocated, so that freeing the
+    /// blocks leaves them apart in the cache.
+    fn apart(
+        handle: &mut HeapHandle,
+        sizes: impl IntoIterator<Item = usize>,
+    ) -> (Vec<*mut c_void>, Vec<*mut c_void>) {
+        let page = get_page_size();
+        let lengths: Vec<_> = sizes
+            .into_iter()
+            .map(|size| (size + Block::size()).next_multiple_of(page))
+            .collect();
+        let total = lengths.iter().map(|length| length + page).sum();
+        let mut block = mem_map(total, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as usize;
+        let (mut ptrs, mut spacers) = (Vec::new(), Vec::new());
+        for length in lengths {
+            for (length, carved) in [(length, &mut ptrs), (page, &mut spacers)] {
+                let capacity = length - Block::size();
+                let ptr = track_large(handle, block as *mut Block, capacity, 0, false);
+                carved.push(hand_out(handle, ptr));
+                block += length;
+            }
+        }
+        (ptrs, spacers)
+    }
+
+    #[test]
+    fn large_cache_reuses_the_best_fit() {
+        let mut handle = HeapHandle::new();
+        let (ptrs, spacers) = apart(&mut handle, [300_000, 200_000, 250_000]);
+        for ptr in &ptrs {
+            heap_free(&mut handle, *ptr).unwrap();
+        }
+        // The most recently freed mapping fits too, but wastes more.
+        let reused = heap_alloc(&mut handle, 190_000);
+        assert_eq!(reused, ptrs[1]);
+        assert_eq!(handle.large_cache_len, 2);
+        for ptr in spacers.into_iter().chain([reused]) {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        heap_trim(&mut handle);
+    }
+
+    #[test]
+    fn neighbouring_cached_mappings_merge() {
+        let page = get_page_size();
+        let length = 64 * page;
+        let before = LIVE_MAPS.get();
+        let mut handle = HeapHandle::new();
+        let start = mem_map(2 * length, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as usize;
+        let [lower, upper] = [start, start + length].map(|block| {
+            let block = block as *mut Block;
+            let ptr = track_large(&mut handle, block, length - Block::size(), 0, false);
+            hand_out(&mut handle, ptr)
+        });
+        heap_free(&mut handle, upper).unwrap();
+        heap_free(&mut handle, lower).unwrap();
+        assert_eq!(handle.large_cache_len, 1);
+        assert_eq!(handle.large_cache_bytes, 2 * length);
+
+        // Neither half holds this on its own.
+        let merged = heap_alloc(&mut handle, length + page);
+        assert_eq!(merged, lower);
+        assert!(handle.large_cache.is_null());
+        heap_free(&mut handle, merged).unwrap();
+        assert_eq!(heap_trim(&mut handle), 2 * length);
+        assert_eq!(LIVE_MAPS.get(), before);
+    }
+
+    #[test]
+    fn large_cache_unmaps_past_its_byte_cap() {
+        let mut handle = HeapHandle::new();
+        let before = LIVE_MAPS.get();
+        // Untouched, so none of this is backed by memory.
+        let size = LARGE_CACHE_BYTES / 2 - 1000;
+        let ptrs: Vec<_> = (0..3).map(|_| heap_alloc(&mut handle, size)).collect();
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        assert!(handle.large_cache_bytes <= LARGE_CACHE_BYTES);
+        assert!(LIVE_MAPS.get() < before + 3);
+        heap_trim(&mut handle);
+        assert_eq!(LIVE_MAPS.get(), before);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn large_cache_benchmark() {
+        let _serial = serial();
+        for cap in [0, LARGE_CACHE_BYTES] {
+            set_large_cache_limit(cap);
+            let start = Instant::now();
+            for _ in 0..10_000 {
+                let ptr = malloc(1 << 20);
+                unsafe {
+                    (ptr as *mut u8).write(1);
+                    free(ptr);
+                }
+            }
+            println!("cache cap {cap}: {:?}", start.elapsed());
+
+            // Several sizes live at once, so the cache has to pick among
+            // its mappings.
+            let sizes = [1 << 20, 3 << 19, 1 << 21, 5 << 18];
+            let start = Instant::now();
+            for i in 0..10_000 {
+                let ptrs = [0, 1, 2, 3].map(|j| malloc(sizes[(i + j) % sizes.len()]));
+                for ptr in ptrs {
+                    unsafe {
+                        (ptr as *mut u8).write(1);
+                        free(ptr);
+                    }
+                }
+            }
+            println!("cache cap {cap}, mixed sizes: {:?}", start.elapsed());
+        }
+    }
+
+    #[test]
+    fn reserved_heaps_serve_allocations_without_mapping() {
+        let mut handle = HeapHandle::new();
+        let mappings = |handle: &HeapHandle| {
+            let mut stats = HeapStats::default();
+            heap_stats(handle, &mut stats);
+            stats.total().mappings
+        };
+        assert!(heap_reserve(&mut handle, 100, 1000));
+        assert!(heap_reserve(&mut handle, 3000, 5));
+        let reserved = mappings(&handle);
+        assert!(reserved > 5);
+
+        let ptrs: Vec<_> = (0..1000).map(|_| heap_alloc(&mut handle, 100)).collect();
+        let exact: Vec<_> = (0..5).map(|_| heap_alloc(&mut handle, 3000)).collect();
+        assert!(ptrs.iter().chain(&exact).all(|ptr| !ptr.is_null()));
+        assert_eq!(mappings(&handle), reserved);
+
+        // Allocations that get a mapping of their own cannot be reserved.
+        assert!(!heap_reserve(&mut handle, 1 << 20, 1));
+        assert_eq!(mappings(&handle), reserved);
+        heap_reset(&mut handle);
+    }
+
+    /// The heaps of `handle` from head to tail, checking the back links on
+    /// the way.
+    fn heap_list(handle: &HeapHandle) -> Vec<*mut Heap> {
+        let mut heaps = Vec::new();
+        let mut heap = handle.heap;
+        let mut previous = ptr::null_mut();
+        while !heap.is_null() {
+            assert_eq!(unsafe { (*heap).previous }, previous);
+            heaps.push(heap);
+            previous = heap;
+            heap = unsafe { (*heap).next };
+        }
+        heaps
+    }
+
+    #[test]
+    fn fragmented_heaps_are_passed_over() {
+        let mut handle = HeapHandle::new();
+        // Fill a tiny heap with blocks of 64 bytes, then free every other one.
+        let block = 64;
+        let per_heap = (TINY_HEAP_ALLOCATION_SIZE - Heap::size()) / (block + Block::size());
+        let ptrs: Vec<_> =
+            (0..per_heap).map(|_| heap_alloc(&mut handle, block - REDZONE)).collect();
+        let heap = handle.heap;
+        assert_eq!(heap_list(&handle), [heap]);
+        for ptr in ptrs.iter().step_by(2) {
+            heap_free(&mut handle, *ptr).unwrap();
+        }
+
+        // Plenty of free bytes are left, but in holes too small for 112.
+        let request = 112;
+        assert!(unsafe { (*heap).free_size } > 4 * (request + Block::size()));
+        let ptr = heap_alloc(&mut handle, request - REDZONE);
+        assert_eq!(heap_list(&handle).len(), 2);
+        assert_ne!(parent_heap(ptr, handle.heap), Some(heap));
+        assert_eq!(heap_verify(&handle), Ok(()));
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn unlinking_heaps_keeps_the_list_consistent() {
+        let mut handle = HeapHandle::new();
+        let config = handle.config;
+        let mapped: Vec<_> = (0..5).map(|_| create_heap(16, &config).unwrap()).collect();
+        for heap in mapped.iter().rev() {
+            link_heap(&mut handle, *heap);
+        }
+        assert_eq!(heap_list(&handle), mapped);
+
+        for (heap, left) in [
+            (mapped[2], [0, 1, 3, 4].as_slice()),
+            (mapped[0], &[1, 3, 4]),
+            (mapped[4], &[1, 3]),
+            (mapped[1], &[3]),
+            (mapped[3], &[]),
+        ] {
+            unlink_heap(heap, &mut handle);
+            assert!(unsafe { (*heap).previous.is_null() && (*heap).next.is_null() });
+            let expected: Vec<_> = left.iter().map(|&i| mapped[i]).collect();
+            assert_eq!(heap_list(&handle), expected);
+        }
+        assert!(handle.heap.is_null());
+        for heap in mapped {
+            unsafe { mem_unmap(heap as *const c_void, (*heap).total_size) }.unwrap();
+        }
+    }
+
+    #[test]
+    fn freeing_empties_heaps_anywhere_in_the_list() {
+        let mut handle = HeapHandle::new();
+        let block = SMALL_HEAP_ALLOCATION_SIZE / 128;
+        let size = block - REDZONE;
+        let per_heap = (SMALL_HEAP_ALLOCATION_SIZE - Heap::size()) / (block + Block::size());
+        let ptrs: Vec<_> = (0..4 * per_heap).map(|_| heap_alloc(&mut handle, size)).collect();
+        let heaps = heap_list(&handle);
+        assert_eq!(heaps.len(), 4);
+
+        // Empty a middle heap, then the head and then the tail.
+        for index in [2, 0, 3] {
+            let owner = heaps[index];
+            let owned: Vec<_> =
+                ptrs.iter().filter(|&&ptr| parent_heap(ptr, owner) == Some(owner)).collect();
+            for ptr in owned {
+                heap_free(&mut handle, *ptr).unwrap();
+            }
+        }
+        assert_eq!(heap_list(&handle), [heaps[1]]);
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn trim_unmaps_empty_heaps() {
+        let mut handle = HeapHandle::new();
+        let mut ptrs = Vec::new();
+        for size in [16, 600, 2000, 48, 3000, 200_000] {
+            ptrs.push(heap_alloc(&mut handle, size));
+        }
+        let survivor = heap_alloc(&mut handle, 16);
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        assert!(heap_trim(&mut handle) > 0);
+        // Only the tiny heap holding `survivor` is left.
+        assert!(!handle.heap.is_null());
+        assert!(unsafe { (*handle.heap).next }.is_null());
+        assert!(handle.large_cache.is_null());
+
+        heap_free(&mut handle, survivor).unwrap();
+        heap_trim(&mut handle);
+        assert!(handle.heap.is_null());
+        assert_eq!(heap_trim(&mut handle), 0);
+    }
+
+    #[test]
+    fn trim_threshold_keeps_empty_heaps_mapped() {
+        for (threshold, unmapped) in [(0, 1), (1 << 20, 0)] {
+            let mut handle =
+                HeapHandle::with_config(AllocatorConfig::new().trim_threshold(threshold));
+            let tiny = heap_alloc(&mut handle, 16);
+            let small = heap_alloc(&mut handle, 600);
+            let before = LIVE_MAPS.get();
+            // The tiny heap is not the anchor, so it is only kept below the
+            // threshold.
+            heap_free(&mut handle, tiny).unwrap();
+            assert_eq!(before - LIVE_MAPS.get(), unmapped);
+            heap_free(&mut handle, small).unwrap();
+            heap_trim(&mut handle);
+            assert!(handle.heap.is_null());
+        }
+    }
+
+    #[test]
+    fn min_split_sets_the_smallest_remainder() {
+        for (min_split, splits) in [(32, true), (256, false)] {
+            let mut handle = HeapHandle::with_config(AllocatorConfig::new().min_split(min_split));
+            let a = heap_alloc(&mut handle, 512);
+            let _b = heap_alloc(&mut handle, 512);
+            heap_free(&mut handle, a).unwrap();
+            // Leaves 112 bytes, a header and 64 bytes of data.
+            let c = heap_alloc(&mut handle, 400);
+            assert_eq!(c, a);
+            let usable = heap_usable_size(&handle, c).unwrap();
+            assert_eq!(usable == 400, splits);
+            assert_eq!(usable == 512, !splits);
+        }
+    }
+
+    #[test]
+    fn global_knobs_tune_the_arenas() {
+        let _serial = serial();
+        assert!(!set_large_cutoff(1024));
+        assert!(set_large_cutoff(4096));
+        let ptr = malloc(8000);
+        let standalone = arena::with_arena(|handle| large_block(ptr, handle.large).is_some());
+        unsafe { free(ptr) };
+        set_min_split(100);
+        set_trim_threshold(1 << 20);
+        let config = default_config();
+        set_large_cutoff(SMALL_HEAP_ALLOCATION_SIZE);
+        set_min_split(MIN_REMAINDER);
+        set_trim_threshold(0);
+
+        assert!(standalone);
+        assert_eq!(config.large_threshold, 4096);
+        assert_eq!(config.min_split, 112);
+        assert_eq!(config.trim_threshold, 1 << 20);
+        assert_eq!(default_config(), AllocatorConfig::new());
+    }
+
+    #[test]
+    fn discarded_memory_is_reused_without_remapping() {
+        let mut handle = HeapHandle::new();
+        let small = heap_alloc(&mut handle, 600);
+        let large = heap_alloc(&mut handle, 300_000);
+        unsafe {
+            (small as *mut u8).write_bytes(0x11, 600);
+            (large as *mut u8).write_bytes(0x22, 300_000);
+        }
+        heap_free(&mut handle, small).unwrap();
+        heap_free(&mut handle, large).unwrap();
+        let before = LIVE_MAPS.get();
+        // The small heap's pages past its first, and all but the large
+        // block's first and last pages.
+        let small_heap = handle.config.small_heap_size;
+        assert!(heap_discard(&mut handle) >= small_heap - PAGE_SIZE + 300_000 - 2 * PAGE_SIZE);
+        assert_eq!(LIVE_MAPS.get(), before);
+
+        assert_eq!(heap_alloc(&mut handle, 600), small);
+        assert_eq!(heap_alloc(&mut handle, 300_000), large);
+        assert_eq!(LIVE_MAPS.get(), before);
+        unsafe {
+            (small as *mut u8).write_bytes(0x33, 600);
+            (large as *mut u8).write_bytes(0x44, 300_000);
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn defragment_merges_adjacent_free_blocks() {
+        let mut handle = HeapHandle::new();
+        let ptrs: Vec<_> = (0..6).map(|_| heap_alloc(&mut handle, 48)).collect();
+        let heap = handle.heap;
+        let block_size = heap_usable_size(&handle, ptrs[0]).unwrap() + REDZONE + Block::size();
+        let chain = |handle: &HeapHandle| {
+            let mut blocks = Vec::new();
+            heap_blocks(handle, &mut |info| blocks.push((info.ptr as *mut c_void, info.free)));
+            blocks
+        };
+        for &ptr in &ptrs[1..4] {
+            release(&handle, ptr);
+        }
+        let free_size = unsafe { (*heap).free_size };
+
+        // Blocks 2 and 3 merge into 1.
+        assert_eq!(heap_defragment(&mut handle), 2 * block_size);
+        let expected = [(ptrs[0], false), (ptrs[1], true), (ptrs[4], false), (ptrs[5], false)];
+        assert_eq!(chain(&handle), expected);
+        assert_eq!(unsafe { (*heap).free_size }, free_size);
+        assert_eq!(heap_defragment(&mut handle), 0);
+
+        // A free last block goes back to the tail.
+        heap_free(&mut handle, ptrs[5]).unwrap();
+        let free_size = unsafe { (*heap).free_size };
+        assert_eq!(heap_defragment(&mut handle), block_size);
+        assert_eq!(chain(&handle), [(ptrs[0], false), (ptrs[1], true), (ptrs[4], false)]);
+        assert_eq!(unsafe { (*heap).free_size }, free_size);
+        assert_eq!(heap_defragment(&mut handle), 0);
+
+        // The merged block serves a request none of its parts could.
+        assert_eq!(heap_alloc(&mut handle, 100), ptrs[1]);
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn free_blocks_without_room_for_a_header_are_not_split() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, 112);
+        let keep = heap_alloc(&mut handle, 16);
+        let usable = heap_usable_size(&handle, ptr).unwrap();
+        let heap = handle.heap;
+        let blocks = unsafe { (*heap).block_count };
+        // An exact fit, then one leaving a header's size less one step.
+        for size in [usable, usable + MIN_ALIGN - Block::size()] {
+            heap_free(&mut handle, ptr).unwrap();
+            assert_eq!(heap_alloc(&mut handle, size), ptr);
+            assert_eq!(heap_usable_size(&handle, ptr), Some(usable));
+            assert_eq!(unsafe { (*heap).block_count }, blocks);
+        }
+        heap_free(&mut handle, ptr).unwrap();
+        heap_free(&mut handle, keep).unwrap();
+    }
+
+    #[test]
+    fn empty_anchor_hands_over_to_successor() {
+        let mut handle = HeapHandle::new();
+        let kept = heap_alloc(&mut handle, 16);
+        // A different size class creates a new heap, which becomes the anchor.
+        let dropped = heap_alloc(&mut handle, 2000);
+        let anchor = handle.heap;
+        assert!(!unsafe { (*anchor).next }.is_null());
+
+        heap_free(&mut handle, dropped).unwrap();
+        assert_ne!(handle.heap, anchor);
+        assert!(unsafe { (*handle.heap).previous }.is_null());
+        assert!(unsafe { (*handle.heap).next }.is_null());
+        assert!(!in_mapped_range(&handle, dropped));
+        assert_eq!(heap_usable_size(&handle, kept), Some(16));
+        heap_free(&mut handle, kept).unwrap();
+        // Only the tiny heap was still mapped.
+        assert_eq!(heap_trim(&mut handle), TINY_HEAP_ALLOCATION_SIZE);
+    }
+
+    #[test]
+    fn guarded_allocation_is_usable() {
+        let mut handle = HeapHandle::new();
+        let size = 200_000;
+        let ptr = alloc_large(&mut handle, size + REDZONE, Mapping::Guarded);
+        let ptr = hand_out(&mut handle, ptr).cast::<u8>();
+        unsafe {
+            ptr.write_bytes(0x5a, size);
+            assert_eq!(*ptr.add(size - 1), 0x5a);
+        }
+        assert_eq!(heap_usable_size(&handle, ptr as *const c_void), Some(size));
+        heap_free(&mut handle, ptr as *const c_void).unwrap();
+        assert!(handle.large.is_null());
+        // Guarded mappings go straight back to the OS.
+        assert!(handle.large_cache.is_null());
+    }
+
+    #[test]
+    fn guarded_allocations_leave_the_large_cache_alone() {
+        let before = LIVE_MAPS.get();
+        let mut handle = HeapHandle::new();
+        let cached = heap_alloc(&mut handle, 200_000);
+        heap_free(&mut handle, cached).unwrap();
+        assert_eq!(handle.large_cache_len, 1);
+        let ptr = alloc_large(&mut handle, 200_000 + REDZONE, Mapping::Guarded);
+        hand_out(&mut handle, ptr);
+        assert_eq!(handle.large_cache_len, 1);
+        heap_reset(&mut handle);
+        assert_eq!(LIVE_MAPS.get(), before);
+    }
+
+    #[test]
+    #[ignore = "faults on purpose, run by guard_page_catches_overflow"]
+    fn overflow_into_guard_page() {
+        let mut handle = HeapHandle::new();
+        let size = 200_000;
+        let ptr = alloc_large(&mut handle, size + REDZONE, Mapping::Guarded);
+        let ptr = hand_out(&mut handle, ptr).cast::<u8>();
+        unsafe { ptr.add(size + REDZONE).write_volatile(1) };
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn guard_page_catches_overflow() {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["tests::overflow_into_guard_page", "--exact", "--ignored"])
+            .output()
+            .unwrap()
+            .status;
+        // SIGSEGV
+        assert_eq!(status.signal(), Some(11));
+    }
+
+    #[cfg(feature = "redzone")]
+    #[test]
+    fn redzone_catches_overflow() {
+        let mut handle = HeapHandle::new();
+        for size in [64, 600, 200_000] {
+            let ptr = heap_alloc(&mut handle, size) as *mut u8;
+            let intact = heap_alloc(&mut handle, size) as *mut u8;
+            // The canary sits right after the usable bytes.
+            let size = heap_usable_size(&handle, ptr as *const c_void).unwrap();
+            unsafe {
+                ptr.write_bytes(1, size + 1);
+                intact.write_bytes(1, size);
+            }
+            assert_eq!(
+                heap_free(&mut handle, ptr as *const c_void),
+                Err(FreeError::Corrupted(ptr as *const c_void))
+            );
+            assert_eq!(heap_free(&mut handle, intact as *const c_void), Ok(()));
+        }
+    }
+
+    #[cfg(feature = "poison")]
+    #[test]
+    fn freed_memory_is_poisoned() {
+        let mut handle = HeapHandle::new();
+        for size in [64, 200_000] {
+            let ptr = heap_alloc(&mut handle, size) as *mut u8;
+            let _neighbour = heap_alloc(&mut handle, 16);
+            let bytes = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+            assert!(bytes.iter().all(|&b| b == 0xAA));
+            bytes.fill(0);
+            heap_free(&mut handle, ptr as *const c_void).unwrap();
+            // The block stays mapped: a tiny heap with a live neighbour, or
+            // a large mapping parked in the cache.
+            let bytes = unsafe { std::slice::from_raw_parts(ptr, size) };
+            assert!(bytes.iter().all(|&b| b == 0xDD));
+        }
+    }
+
+    #[test]
+    fn stats_track_live_and_free_bytes() {
+        let mut handle = HeapHandle::new();
+        let tiny = heap_alloc(&mut handle, 16);
+        let _small = heap_alloc(&mut handle, 600);
+        let _large = heap_alloc(&mut handle, 200_000);
+        let mut stats = HeapStats::default();
+        heap_stats(&handle, &mut stats);
+
+        assert_eq!(stats.tiny.mappings, 1);
+        assert_eq!(stats.tiny.mapped_bytes, TINY_HEAP_ALLOCATION_SIZE);
+        let used = 16 + REDZONE + Block::size();
+        assert_eq!(stats.tiny.free_bytes, TINY_HEAP_ALLOCATION_SIZE - Heap::size() - used);
+        assert_eq!(stats.small.live_blocks, 1);
+        let mapped = (200_000 + REDZONE + Block::size()).next_multiple_of(get_page_size());
+        assert_eq!(stats.large.mapped_bytes, mapped);
+        assert_eq!(stats.large.free_bytes, 0);
+        let total = stats.total();
+        assert_eq!(total.mappings, 3);
+        assert_eq!(total.live_blocks, 3);
+
+        heap_free(&mut handle, tiny).unwrap();
+        let mut stats = HeapStats::default();
+        heap_stats(&handle, &mut stats);
+        // The emptied tiny heap was not the anchor, so it is gone.
+        assert_eq!(stats.tiny, GroupStats::default());
+        assert_eq!(stats.total().live_blocks, 2);
+    }
+
+    #[test]
+    fn global_stats_see_this_thread() {
+        let _serial = serial();
+        let ptr = malloc(100);
+        assert!(stats().total().live_blocks >= 1);
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn blocks_are_reported_in_order() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 16);
+        let b = heap_alloc(&mut handle, 32);
+        let c = heap_alloc(&mut handle, 48);
+        heap_free(&mut handle, b).unwrap();
+        let mut blocks = Vec::new();
+        heap_blocks(&handle, &mut |info| blocks.push(info));
+        let expected = [(a, 16, false), (b, 32, true), (c, 48, false)]
+            .map(|(ptr, size, free)| BlockInfo { ptr, size, free });
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn freeing_between_free_neighbours_leaves_one_block() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 16);
+        let b = heap_alloc(&mut handle, 32);
+        let c = heap_alloc(&mut handle, 48);
+        let d = heap_alloc(&mut handle, 16);
+        heap_free(&mut handle, a).unwrap();
+        heap_free(&mut handle, c).unwrap();
+        heap_free(&mut handle, b).unwrap();
+        let mut blocks = Vec::new();
+        heap_blocks(&handle, &mut |info| blocks.push(info));
+        let merged = 16 + 32 + 48 + 2 * (Block::size() + REDZONE);
+        let expected = [(a, merged, true), (d, 16, false)]
+            .map(|(ptr, size, free)| BlockInfo { ptr, size, free });
+        assert_eq!(blocks, expected);
+        assert_eq!(unsafe { (*handle.heap).block_count }, 2);
+    }
+
+    #[test]
+    fn merges_run_past_several_free_neighbours() {
+        for last in [true, false] {
+            let mut handle = HeapHandle::new();
+            let ptrs: Vec<_> = [16, 32, 48, 64].map(|size| heap_alloc(&mut handle, size)).into();
+            let _keep = heap_alloc(&mut handle, 16);
+            let (run, freed) = if last { (&ptrs[..3], ptrs[3]) } else { (&ptrs[1..], ptrs[0]) };
+            for ptr in run {
+                release(&handle, *ptr);
+            }
+            heap_free(&mut handle, freed).unwrap();
+
+            let mut blocks = Vec::new();
+            heap_blocks(&handle, &mut |info| blocks.push(info));
+            let merged = 16 + 32 + 48 + 64 + 3 * (Block::size() + REDZONE);
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0], BlockInfo { ptr: ptrs[0], size: merged, free: true });
+            assert_eq!(unsafe { (*handle.heap).block_count }, 2);
+        }
+    }
+
+    #[test]
+    fn dump_lists_heaps_and_blocks() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 48);
+        let _b = heap_alloc(&mut handle, 80);
+        let large = heap_alloc(&mut handle, 200_000);
+        heap_free(&mut handle, a).unwrap();
+        let mut out = String::new();
+        heap_dump(&handle, &mut out).unwrap();
+
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("tiny heap "));
+        assert!(lines[0].ends_with(", 2 blocks"));
+        assert_eq!(lines[1], format!("  {a:p}: 48 bytes, free"));
+        assert!(lines[2].ends_with(": 80 bytes, used"));
+        assert_eq!(lines[3], format!("large mapping {large:p}: 200000 bytes, used"));
+    }
+
+    #[cfg(feature = "size-map")]
+    #[test]
+    fn side_table_tracks_requested_sizes() {
+        use crate::lookup_size;
+
+        let _serial = serial();
+        // With `ffi` the box comes from this allocator too.
+        if !cfg!(feature = "ffi") {
+            let foreign = Box::new(0u64);
+            assert_eq!(lookup_size(&*foreign as *const u64 as *const c_void), None);
+        }
+        let ptr = malloc(37);
+        assert_eq!(lookup_size(ptr), Some(37));
+        let ptr = unsafe { realloc(ptr, 3000) };
+        assert_eq!(lookup_size(ptr), Some(3000));
+        let aligned = aligned_alloc(256, 10);
+        assert_eq!(lookup_size(aligned), Some(10));
+        unsafe {
+            free(ptr);
+            free(aligned);
+        }
+        assert_eq!(lookup_size(ptr), None);
+        assert_eq!(lookup_size(aligned), None);
+    }
+
+    #[cfg(feature = "leak-check")]
+    #[test]
+    fn leaks_keep_their_call_site() {
+        use std::panic::Location;
+
+        use crate::heap_leaks;
+
+        let mut handle = HeapHandle::new();
+        let site = Location::caller();
+        handle.site = Some(site);
+        let freed = heap_alloc(&mut handle, 24);
+        let leaked = heap_alloc(&mut handle, 40);
+        heap_free(&mut handle, freed).unwrap();
+        let mut leaks = Vec::new();
+        heap_leaks(&handle, &mut |leak| leaks.push(leak));
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(leaks[0].ptr, leaked as *const c_void);
+        assert_eq!(leaks[0].site, Some(site));
+    }
+
+    #[cfg(feature = "leak-check")]
+    #[test]
+    fn report_leaks_names_the_caller() {
+        let _serial = serial();
+        use crate::report_leaks;
+
+        let ptr = malloc(72);
+        let line = line!() - 1;
+        let leak = report_leaks().into_iter().find(|leak| std::ptr::eq(leak.ptr, ptr));
+        let site = leak.unwrap().site.unwrap();
+        assert_eq!((site.file(), site.line()), (file!(), line));
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn huge_pages_succeed_or_fall_back() {
+        let mut handle = HeapHandle::new();
+        let size = 3 * 1024 * 1024;
+        let ptr = alloc_large(&mut handle, size, Mapping::HugePages);
+        let ptr = hand_out(&mut handle, ptr).cast::<u8>();
+        assert!(!ptr.is_null());
+        let usable = heap_usable_size(&handle, ptr as *const c_void).unwrap();
+        let mapped = usable + REDZONE + Block::size();
+        // Either two whole huge pages, or exactly what was asked for.
+        assert!(mapped == 4 * 1024 * 1024 || mapped == size + Block::size());
+        unsafe { ptr.write_bytes(0x11, usable) };
+        heap_free(&mut handle, ptr as *const c_void).unwrap();
+        assert_eq!(heap_trim(&mut handle), mapped.next_multiple_of(get_page_size()));
+    }
+
+    #[test]
+    fn prefaulted_large_blocks_are_usable() {
+        let _serial = serial();
+        set_prefault(true);
+        let size = 1024 * 1024;
+        let ptr = malloc(size) as *mut u8;
+        set_prefault(false);
+        assert!(!ptr.is_null());
+        let bytes = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+        bytes.fill(0x3c);
+        assert!(bytes.iter().all(|&b| b == 0x3c));
+        unsafe { free(ptr as *mut c_void) };
+    }
+
+    #[test]
+    fn heaps_are_whole_system_pages() {
+        let mut handle = HeapHandle::new();
+        // One heap of each group, the last of its exact size.
+        let ptrs = [16, 600, 3000].map(|size| heap_alloc(&mut handle, size));
+        let mut heap = handle.heap;
+        while !heap.is_null() {
+            assert_eq!(heap as usize % get_page_size(), 0);
+            assert_eq!(unsafe { (*heap).total_size } % get_page_size(), 0);
+            heap = unsafe { (*heap).next };
+        }
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+        heap_reset(&mut handle);
+    }
+
+    #[test]
+    fn zero_size_allocations_are_unique() {
+        let _serial = serial();
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 0);
+        let b = heap_alloc(&mut handle, 0);
+        let c = heap_alloc(&mut handle, 8);
+        assert!(!a.is_null());
+        assert_ne!(a, b);
+        assert_ne!(b, c);
+        assert_eq!(heap_usable_size(&handle, a), Some(MIN_ALIGN));
+        heap_free(&mut handle, a).unwrap();
+        heap_free(&mut handle, b).unwrap();
+        heap_free(&mut handle, c).unwrap();
+
+        let ptr = malloc(0);
+        assert!(!ptr.is_null());
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn splits_never_leave_tiny_remainders() {
+        let mut handle = HeapHandle::new();
+        let a = heap_alloc(&mut handle, 96);
+        let b = heap_alloc(&mut handle, 16);
+        heap_free(&mut handle, a).unwrap();
+        // Splitting would leave a 16 byte block behind, so nothing is split off.
+        let c = heap_alloc(&mut handle, 96 - Block::size() - MIN_ALIGN);
+        assert_eq!(c, a);
+        let block = unsafe { block_unshift!(c) };
+        assert_eq!(unsafe { (*block).data_size() }, 96 + REDZONE);
+        assert_eq!(unsafe { (*block).next }, unsafe { block_unshift!(b) });
+
+        heap_free(&mut handle, c).unwrap();
+        let d = heap_alloc(&mut handle, 95);
+        assert_eq!(d, a);
+        assert_eq!(unsafe { (*block).data_size() }, 96 + REDZONE);
+    }
+
+    #[test]
+    fn large_group_heap_accounting_stays_sane() {
+        let mut handle = HeapHandle::new();
+        let size = 4000;
+        let ptr = heap_alloc(&mut handle, size);
+        let heap = handle.heap;
+        let full = unsafe { (*heap).total_size } - Heap::size();
+        // An exact-size heap: the block uses every byte of it, rounding
+        // included.
+        assert_eq!(unsafe { (*heap).free_size }, 0);
+        assert!(heap_usable_size(&handle, ptr).unwrap() - size < get_page_size());
+        heap_free(&mut handle, ptr).unwrap();
+        assert_eq!(unsafe { (*heap).free_size }, full);
+
+        let a = heap_alloc(&mut handle, 2000);
+        let b = heap_alloc(&mut handle, 1500);
+        assert_eq!(handle.heap, heap);
+        assert!(unsafe { (*heap).free_size } <= full);
+        heap_free(&mut handle, a).unwrap();
+        heap_free(&mut handle, b).unwrap();
+        assert_eq!(unsafe { (*heap).free_size }, full);
+    }
+
+    #[test]
+    fn try_malloc_returns_none_on_failure() {
+        use crate::mmap::FAIL_MAPS;
+
+        let ptr = try_malloc(64).unwrap();
+        unsafe { ptr.as_ptr().write_bytes(0x42, 64) };
+        // Too big for the large-mapping cache, so it needs a new mapping.
+        FAIL_MAPS.set(1);
+        assert_eq!(try_malloc(100 << 20), None);
+        FAIL_MAPS.set(0);
+        assert_eq!(try_malloc(usize::MAX), None);
+        unsafe { free(ptr.as_ptr() as *mut c_void) };
+    }
+
+    #[test]
+    fn slices_are_written_and_freed_without_unsafe() {
+        let slice = alloc_slice(100).unwrap();
+        assert!(slice.len() >= 100);
+        assert!(slice.iter().all(|&byte| byte == 0));
+        slice.fill(0x5a);
+        assert!(slice.iter().all(|&byte| byte == 0x5a));
+        free_slice(slice).unwrap();
+
+        // Parts of a slice are refused, or the rest would dangle.
+        let slice = alloc_slice(200).unwrap();
+        let ptr = slice.as_mut_ptr();
+        let (head, tail) = slice.split_at_mut(64);
+        assert_eq!(free_slice(tail), Err(FreeError::InvalidPointer));
+        assert_eq!(free_slice(head), Err(FreeError::InvalidPointer));
+        unsafe { free(ptr.cast()) };
+    }
+
+    // Without `std` the arena it drains is shared with every other test, and
+    // with `ffi` the vector holding the pointers runs out of memory too.
+    #[cfg(all(feature = "std", not(feature = "ffi")))]
+    #[test]
+    fn emergency_pool_serves_allocations_when_memory_runs_out() {
+        let _serial = serial();
+        use crate::mmap::FAIL_MAPS;
+        use crate::{emergency_reserve, try_malloc_emergency};
+
+        assert!(emergency_reserve());
+        // Have this thread's arena ready before mappings start failing.
+        let mut ptrs = vec![try_malloc(64).unwrap()];
+        FAIL_MAPS.set(usize::MAX);
+        while let Some(ptr) = try_malloc(64) {
+            ptrs.push(ptr);
+        }
+        let ptr = try_malloc_emergency(64).unwrap();
+        unsafe { ptr.as_ptr().write_bytes(0x11, 64) };
+        assert!(try_malloc_emergency(4096).is_none());
+        FAIL_MAPS.set(0);
+
+        assert!(contains(ptr.as_ptr().cast()));
+        for ptr in ptrs.into_iter().chain([ptr]) {
+            unsafe { free(ptr.as_ptr().cast()) };
+        }
+    }
+
+    #[test]
+    fn failed_mappings_retry_after_trim() {
+        let _serial = serial();
+        use crate::mmap::FAIL_MAPS;
+
+        let mut handle = HeapHandle::new();
+        let cached = heap_alloc(&mut handle, 300_000);
+        heap_free(&mut handle, cached).unwrap();
+        assert!(!handle.large_cache.is_null());
+        let before = LIVE_MAPS.get();
+
+        set_trim_on_oom(true);
+        // Too big for the cached mapping, so it needs a new one.
+        FAIL_MAPS.set(1);
+        let ptr = heap_alloc(&mut handle, 1 << 20);
+        set_trim_on_oom(false);
+        assert!(!ptr.is_null());
+        assert!(handle.large_cache.is_null());
+        // The cached mapping went, the new one came.
+        assert_eq!(LIVE_MAPS.get(), before);
+        unsafe { (ptr as *mut u8).write_bytes(0x5a, 1 << 20) };
+        heap_free(&mut handle, ptr).unwrap();
+
+        // Off, or with nothing to release, the failure stands.
+        FAIL_MAPS.set(1);
+        assert!(heap_alloc(&mut handle, 2 << 20).is_null());
+        set_trim_on_oom(true);
+        heap_trim(&mut handle);
+        FAIL_MAPS.set(1);
+        assert!(heap_alloc(&mut handle, 2 << 20).is_null());
+        set_trim_on_oom(false);
+        FAIL_MAPS.set(0);
+    }
+
+    #[test]
+    fn failed_mappings_return_null() {
+        let _serial = serial();
+        use crate::mmap::FAIL_MAPS;
+
+        let mut handle = HeapHandle::new();
+        for size in [16, 600, 4000, 200_000] {
+            FAIL_MAPS.set(1);
+            assert!(heap_alloc(&mut handle, size).is_null());
+        }
+        FAIL_MAPS.set(2);
+        assert!(alloc_large(&mut handle, 4 << 20, Mapping::HugePages).is_null());
+        FAIL_MAPS.set(0);
+        assert!(handle.heap.is_null());
+        assert!(handle.large.is_null());
+
+        let ptr = heap_alloc(&mut handle, 16);
+        assert!(!ptr.is_null());
+        heap_free(&mut handle, ptr).unwrap();
+        // The kernel refuses a mapping this size on its own.
+        assert!(malloc(usize::MAX / 2).is_null());
+    }
+
+    #[test]
+    fn reset_unmaps_everything() {
+        let mut handle = HeapHandle::new();
+        let mut ptrs = Vec::new();
+        for size in [16, 600, 4000, 200_000] {
+            ptrs.push(heap_alloc(&mut handle, size));
+        }
+        let cached = heap_alloc(&mut handle, 300_000);
+        heap_free(&mut handle, cached).unwrap();
+        heap_reset(&mut handle);
+        assert!(handle.heap.is_null());
+        assert!(handle.large.is_null());
+        assert!(handle.large_cache.is_null());
+        let mut stats = HeapStats::default();
+        heap_stats(&handle, &mut stats);
+        assert_eq!(stats, HeapStats::default());
+        for ptr in ptrs {
+            assert!(!in_mapped_range(&handle, ptr));
+        }
+
+        // The handle is usable again.
+        let ptr = heap_alloc(&mut handle, 16);
+        heap_free(&mut handle, ptr).unwrap();
+    }
+
+    #[test]
+    fn interior_pointers_are_rejected() {
+        let mut handle = HeapHandle::new();
+        let ptr = heap_alloc(&mut handle, 64) as *mut u8;
+        let layout = Layout::from_size_align(64, 64).unwrap();
+        let aligned = heap_allocate(&mut handle, layout) as *mut u8;
+        let large = heap_alloc(&mut handle, 200_000) as *mut u8;
+        for base in [ptr, aligned, large] {
+            unsafe { base.write_bytes(0x5a, 64) };
+            for offset in [1, 4, 8, 16] {
+                let interior = unsafe { base.add(offset) } as *const c_void;
+                assert_eq!(heap_usable_size(&handle, interior), None);
+                assert_eq!(heap_free(&mut handle, interior), Err(FreeError::InvalidPointer));
+            }
+            heap_free(&mut handle, base as *const c_void).unwrap();
+        }
+    }
+
+    #[test]
+    fn interleaved_heap_and_large_frees() {
+        let mut handle = HeapHandle::new();
+        let survivor = heap_alloc(&mut handle, 32) as *mut u8;
+        unsafe { survivor.write_bytes(0x33, 32) };
+        let sizes = [16, 200_000, 600, 300_000, 4000, 150_000];
+        let ptrs: Vec<_> = sizes.iter().map(|&size| heap_alloc(&mut handle, size)).collect();
+        for i in [1, 0, 3, 2, 5, 4] {
+            heap_free(&mut handle, ptrs[i]).unwrap();
+            // A pointer that is no longer live never reaches an unmap.
+            assert!(heap_free(&mut handle, ptrs[i]).is_err());
+        }
+        let mut stats = HeapStats::default();
+        heap_stats(&handle, &mut stats);
+        assert_eq!(stats.total().live_blocks, 1);
+        let bytes = unsafe { std::slice::from_raw_parts(survivor, 32) };
+        assert!(bytes.iter().all(|&b| b == 0x33));
+        heap_free(&mut handle, survivor as *const c_void).unwrap();
+    }
+
+    #[test]
+    fn block_data_is_16_byte_aligned() {
+        assert_eq!(Block::size() % 16, 0);
+        assert_eq!(Heap::size() % 16, 0);
+        let mut handle = HeapHandle::new();
+        let ptrs: Vec<_> = [1, 8, 24, 100, 700, 3000, 200_000]
+            .iter()
+            .map(|&size| heap_alloc(&mut handle, size))
+            .collect();
+        for &ptr in &ptrs {
+            let block = unsafe { block_unshift!(ptr) };
+            assert_eq!(unsafe { block_shift!(block) } as usize % 16, 0);
+        }
+        for ptr in ptrs {
+            heap_free(&mut handle, ptr).unwrap();
+        }
+    }
+}
@@ -1,12 +1,188 @@
-use std::{
-    io::ErrorKind,
-    mem::transmute,
-    os::raw::c_void,
-};
+use core::{ffi::c_void, ptr};
+#[cfg(windows)]
+use core::mem;
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "std")]
+pub use std::io::Error;
+#[cfg(feature = "std")]
+use std::sync::OnceLock;
+
+/// An OS error code, standing in for `std::io::Error` without `std`.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(i32);
+
+#[cfg(not(feature = "std"))]
+impl Error {
+    pub fn from_raw_os_error(code: i32) -> Self {
+        Self(code)
+    }
+
+    pub fn last_os_error() -> Self {
+        Self(errno())
+    }
+
+    #[allow(dead_code)]
+    pub fn raw_os_error(&self) -> Option<i32> {
+        Some(self.0)
+    }
+}
+
+#[cfg(unix)]
+const PROT_NONE: i32 = 0;
+#[cfg(unix)]
+const PROT_READ: i32 = 0x1;
+#[cfg(unix)]
+const PROT_WRITE: i32 = 0x2;
+#[cfg(unix)]
+const MAP_PRIVATE: i32 = 0x2;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAP_ANONYMOUS: i32 = 0x20;
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+const MAP_ANONYMOUS: i32 = 0x1000;
+
+#[cfg(unix)]
+pub const MMAP_PROT_NONE: i32 = PROT_NONE;
+#[cfg(unix)]
+pub const MMAP_PROT_READ_WRITE: i32 = PROT_READ | PROT_WRITE;
+#[cfg(unix)]
+const MMAP_ANON_FLAG: i32 = MAP_PRIVATE | MAP_ANONYMOUS;
+
+#[cfg(test)]
+thread_local! {
+    /// How many of this thread's next `mem_map` calls fail, to exercise the
+    /// out-of-memory paths.
+    pub static FAIL_MAPS: Cell<usize> = const { Cell::new(0) };
+    /// Mappings this thread made and has not unmapped yet, to check that
+    /// nothing is leaked.
+    pub static LIVE_MAPS: Cell<isize> = const { Cell::new(0) };
+    /// Where those mappings start, as one unmap can cover several of them,
+    /// and how many are recorded. A fixed array, since growing a vector
+    /// would allocate, and with `ffi` that re-enters the exported `malloc`
+    /// while an arena is locked.
+    static MAPPED: RefCell<([usize; MAPPED_SLOTS], usize)> =
+        const { RefCell::new(([0; MAPPED_SLOTS], 0)) };
+}
+
+/// Mapping starts recorded per thread. Past that many they go unrecorded,
+/// and unmapping one counts as a single mapping.
+#[cfg(test)]
+const MAPPED_SLOTS: usize = 4096;
 
-const MMAP_PROT_FLAG: i32 = 3;
-const MMAP_ANON_FLAG: i32 = 34;
+/// Consumes one injected failure, if any are left.
+#[cfg(test)]
+fn injected_failure() -> bool {
+    FAIL_MAPS.with(|left| {
+        let fail = left.get() > 0;
+        left.set(left.get().saturating_sub(1));
+        fail
+    })
+}
+
+#[cfg(not(test))]
+fn injected_failure() -> bool {
+    false
+}
+
+#[cfg(test)]
+fn count_map(add: *const c_void) {
+    MAPPED.with(|mapped| {
+        let (starts, len) = &mut *mapped.borrow_mut();
+        if *len < MAPPED_SLOTS {
+            starts[*len] = add as usize;
+            *len += 1;
+        }
+    });
+    LIVE_MAPS.with(|live| live.set(live.get() + 1));
+}
+
+/// Counts the mappings starting in the unmapped range, which holds several
+/// once neighbouring ones were merged. A mapping another thread made still
+/// counts as one.
+#[cfg(test)]
+fn count_unmap(add: *const c_void, length: usize) {
+    let range = add as usize..add as usize + length.max(1);
+    let covered = MAPPED.with(|mapped| {
+        let (starts, len) = &mut *mapped.borrow_mut();
+        let mut covered = 0;
+        let mut i = 0;
+        while i < *len {
+            if range.contains(&starts[i]) {
+                *len -= 1;
+                starts[i] = starts[*len];
+                covered += 1;
+            } else {
+                i += 1;
+            }
+        }
+        covered
+    });
+    LIVE_MAPS.with(|live| live.set(live.get() - covered.max(1) as isize));
+}
+
+#[cfg(all(test, target_os = "linux"))]
+fn count_remap(add: *const c_void, moved: *const c_void) {
+    MAPPED.with(|mapped| {
+        let (starts, len) = &mut *mapped.borrow_mut();
+        for start in starts[..*len].iter_mut().filter(|start| **start == add as usize) {
+            *start = moved as usize;
+        }
+    });
+}
 
+#[cfg(not(test))]
+fn count_map(_add: *const c_void) {}
+
+#[cfg(not(test))]
+fn count_unmap(_add: *const c_void, _length: usize) {}
+
+#[cfg(all(not(test), target_os = "linux"))]
+fn count_remap(_add: *const c_void, _moved: *const c_void) {}
+
+#[cfg(unix)]
+const ENOMEM: i32 = 12;
+#[cfg(unix)]
+const MADV_DONTNEED: i32 = 4;
+#[cfg(target_os = "linux")]
+const MREMAP_MAYMOVE: i32 = 1;
+#[cfg(target_os = "linux")]
+const EINVAL: i32 = 22;
+#[cfg(target_os = "linux")]
+const ENOSYS: i32 = 38;
+#[cfg(target_os = "linux")]
+const MPOL_BIND: usize = 2;
+/// Moves the pages already faulted in over to the new policy.
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: usize = 1 << 1;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SYS_MBIND: Option<i64> = Some(237);
+#[cfg(all(target_os = "linux", any(target_arch = "aarch64", target_arch = "riscv64")))]
+const SYS_MBIND: Option<i64> = Some(235);
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64"))
+))]
+const SYS_MBIND: Option<i64> = None;
+#[cfg(windows)]
+const ERROR_NOT_ENOUGH_MEMORY: i32 = 8;
+
+/// Extra `mem_map` flags.
+pub const MMAP_NO_FLAGS: i32 = 0;
+/// Back the mapping with huge pages; the length must be a multiple of the
+/// huge page size. Only Linux supports it, elsewhere it is ignored.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const MMAP_HUGE_PAGES: i32 = 0x40000;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub const MMAP_HUGE_PAGES: i32 = 0;
+/// Fault every page in while mapping, so first touches do not stall. Only
+/// Linux supports it, elsewhere it is ignored.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const MMAP_POPULATE: i32 = 0x8000;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub const MMAP_POPULATE: i32 = 0;
+
+#[cfg(unix)]
 extern "C" {
     fn mmap(
         addr: *const c_void,
@@ -17,47 +193,279 @@ extern "C" {
         offset: u64,
     ) -> *const c_void;
     fn munmap(add: *const c_void, length: usize) -> i32;
+    fn mprotect(add: *const c_void, length: usize, prot: i32) -> i32;
+    fn madvise(add: *const c_void, length: usize, advice: i32) -> i32;
+    #[cfg(target_os = "linux")]
+    fn syscall(number: i64, ...) -> i64;
+    #[cfg(target_os = "linux")]
+    fn mremap(
+        add: *const c_void,
+        length: usize,
+        new_length: usize,
+        flags: i32,
+        ...
+    ) -> *const c_void;
     fn getpagesize() -> usize;
+    #[cfg(all(not(feature = "std"), any(target_os = "linux", target_os = "android")))]
+    fn __errno_location() -> *mut i32;
+    #[cfg(all(not(feature = "std"), not(any(target_os = "linux", target_os = "android"))))]
+    fn __error() -> *mut i32;
 }
 
-pub fn mem_map(length: usize) -> Option<*const std::ffi::c_void> {
+#[cfg(all(unix, not(feature = "std")))]
+fn errno() -> i32 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let errno = unsafe { __errno_location() };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let errno = unsafe { __error() };
+    unsafe { *errno }
+}
+
+#[cfg(unix)]
+pub fn mem_map(length: usize, prot: i32, flags: i32) -> Result<*const c_void, Error> {
+    if injected_failure() {
+        return Err(Error::from_raw_os_error(ENOMEM));
+    }
     unsafe {
         let block = mmap(
-            0 as *const c_void,
+            ptr::null(),
             length,
-            MMAP_PROT_FLAG,
-            MMAP_ANON_FLAG,
+            prot,
+            MMAP_ANON_FLAG | flags,
             -1,
             0,
         );
-        match block != transmute(-1 as isize)
-        {
-            true => Some(block),
-            false => None,
+        match block as isize != -1 {
+            true => {
+                count_map(block);
+                Ok(block)
+            }
+            false => Err(Error::last_os_error()),
         }
     }
 }
 
-pub fn mem_unmap(add: *const c_void, length: usize) -> Result<(), ErrorKind> {
+#[cfg(unix)]
+pub fn mem_unmap(add: *const c_void, length: usize) -> Result<(), Error> {
     unsafe {
         match munmap(add, length) < 0 {
-            true => Err(ErrorKind::Other),
+            true => Err(Error::last_os_error()),
+            false => {
+                count_unmap(add, length);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn mem_protect(add: *const c_void, length: usize, prot: i32) -> Result<(), Error> {
+    unsafe {
+        match mprotect(add, length, prot) < 0 {
+            true => Err(Error::last_os_error()),
             false => Ok(()),
         }
     }
 }
 
-#[allow(dead_code)]
-pub fn get_page_size() -> usize {
+/// Hands the pages of a page-aligned range back to the OS while keeping the
+/// range mapped. The range stays usable; its contents are lost.
+#[cfg(unix)]
+pub fn mem_discard(add: *const c_void, length: usize) -> Result<(), Error> {
+    unsafe {
+        match madvise(add, length, MADV_DONTNEED) < 0 {
+            true => Err(Error::last_os_error()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Resizes the mapping at `add` to `new_length`, moving it elsewhere when it
+/// cannot grow where it is. Its contents come along without being copied.
+/// Returns the address of the mapping, which is the only valid one
+/// afterwards.
+#[cfg(target_os = "linux")]
+pub fn mem_remap(
+    add: *const c_void,
+    length: usize,
+    new_length: usize,
+) -> Result<*const c_void, Error> {
+    unsafe {
+        let moved = mremap(add, length, new_length, MREMAP_MAYMOVE);
+        match moved as isize != -1 {
+            true => {
+                count_remap(add, moved);
+                Ok(moved)
+            }
+            false => Err(Error::last_os_error()),
+        }
+    }
+}
+
+/// Binds the pages of a page-aligned range to NUMA node `node`, moving those
+/// already faulted in. C libraries do not wrap `mbind`, so this makes the
+/// system call itself; nodes from 64 up and architectures it does not know
+/// the call number of are refused.
+#[cfg(target_os = "linux")]
+pub fn mem_bind(add: *const c_void, length: usize, node: usize) -> Result<(), Error> {
+    let Some(number) = SYS_MBIND else {
+        return Err(Error::from_raw_os_error(ENOSYS));
+    };
+    if node >= u64::BITS as usize {
+        return Err(Error::from_raw_os_error(EINVAL));
+    }
+    let mask: u64 = 1 << node;
+    // The kernel reads one bit less than `maxnode` says.
+    let maxnode = u64::BITS as usize + 1;
+    let result = unsafe {
+        syscall(
+            number,
+            add,
+            length,
+            MPOL_BIND,
+            &mask as *const u64,
+            maxnode,
+            MPOL_MF_MOVE,
+        )
+    };
+    match result < 0 {
+        true => Err(Error::last_os_error()),
+        false => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn query_page_size() -> usize {
     unsafe { getpagesize() }
 }
 
+/// `PAGE_NOACCESS`
+#[cfg(windows)]
+pub const MMAP_PROT_NONE: i32 = 0x01;
+/// `PAGE_READWRITE`
+#[cfg(windows)]
+pub const MMAP_PROT_READ_WRITE: i32 = 0x04;
+#[cfg(windows)]
+const MEM_COMMIT: u32 = 0x1000;
+#[cfg(windows)]
+const MEM_RESERVE: u32 = 0x2000;
+#[cfg(windows)]
+const MEM_RELEASE: u32 = 0x8000;
+#[cfg(windows)]
+const MEM_RESET: u32 = 0x80000;
+
+#[cfg(windows)]
+#[repr(C)]
+struct SystemInfo {
+    processor_architecture: u16,
+    reserved: u16,
+    page_size: u32,
+    minimum_application_address: *mut c_void,
+    maximum_application_address: *mut c_void,
+    active_processor_mask: usize,
+    number_of_processors: u32,
+    processor_type: u32,
+    allocation_granularity: u32,
+    processor_level: u16,
+    processor_revision: u16,
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn VirtualAlloc(addr: *const c_void, size: usize, kind: u32, protect: u32) -> *const c_void;
+    fn VirtualFree(addr: *const c_void, size: usize, kind: u32) -> i32;
+    fn VirtualProtect(addr: *const c_void, size: usize, protect: u32, old: *mut u32) -> i32;
+    fn GetSystemInfo(info: *mut SystemInfo);
+    #[cfg(not(feature = "std"))]
+    fn GetLastError() -> u32;
+}
+
+#[cfg(all(windows, not(feature = "std")))]
+fn errno() -> i32 {
+    unsafe { GetLastError() as i32 }
+}
+
+#[cfg(windows)]
+pub fn mem_map(length: usize, prot: i32, _flags: i32) -> Result<*const c_void, Error> {
+    if injected_failure() {
+        return Err(Error::from_raw_os_error(ERROR_NOT_ENOUGH_MEMORY));
+    }
+    let block = unsafe { VirtualAlloc(ptr::null(), length, MEM_COMMIT | MEM_RESERVE, prot as u32) };
+    match block.is_null() {
+        true => Err(Error::last_os_error()),
+        false => {
+            count_map(block);
+            Ok(block)
+        }
+    }
+}
+
+/// Releases the whole reservation starting at `add`; Windows cannot release
+/// part of one, so `length` is only there to match the `munmap` signature.
+#[cfg(windows)]
+pub fn mem_unmap(add: *const c_void, _length: usize) -> Result<(), Error> {
+    match unsafe { VirtualFree(add, 0, MEM_RELEASE) } == 0 {
+        true => Err(Error::last_os_error()),
+        false => {
+            count_unmap(add, 0);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn mem_protect(add: *const c_void, length: usize, prot: i32) -> Result<(), Error> {
+    let mut old = 0;
+    match unsafe { VirtualProtect(add, length, prot as u32, &mut old) } == 0 {
+        true => Err(Error::last_os_error()),
+        false => Ok(()),
+    }
+}
+
+/// Hands the pages of a page-aligned range back to the OS while keeping the
+/// range reserved and committed. The range stays usable; its contents are
+/// lost.
+#[cfg(windows)]
+pub fn mem_discard(add: *const c_void, length: usize) -> Result<(), Error> {
+    let reset = unsafe { VirtualAlloc(add, length, MEM_RESET, MMAP_PROT_READ_WRITE as u32) };
+    match reset.is_null() {
+        true => Err(Error::last_os_error()),
+        false => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+fn query_page_size() -> usize {
+    let mut info = mem::MaybeUninit::<SystemInfo>::uninit();
+    unsafe {
+        GetSystemInfo(info.as_mut_ptr());
+        info.assume_init().page_size as usize
+    }
+}
+
+/// The system page size, asked for once.
+#[cfg(feature = "std")]
+static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+
+/// The size of a system page. Only the first call asks the OS, except
+/// without `std`, where there is nothing to keep the answer in.
+pub fn get_page_size() -> usize {
+    #[cfg(feature = "std")]
+    return *PAGE_SIZE.get_or_init(query_page_size);
+    #[cfg(not(feature = "std"))]
+    query_page_size()
+}
+
 
 #[cfg(test)]
 mod map_tests{
     use std::ffi::c_void;
 
-    use super::mem_unmap;
+    use super::{
+        get_page_size, mem_discard, mem_map, mem_unmap, MMAP_NO_FLAGS, MMAP_POPULATE,
+        MMAP_PROT_READ_WRITE,
+    };
 
     #[test]
     #[should_panic]
@@ -65,4 +473,95 @@ mod map_tests{
         let block = 56 as *const c_void;
         mem_unmap(block, 64).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn map_write_unmap() {
+        let length = 4 * get_page_size();
+        let block = mem_map(length, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as *mut u8;
+        unsafe {
+            block.write_bytes(0x7f, length);
+            assert_eq!(*block.add(length - 1), 0x7f);
+        }
+        mem_unmap(block as *const c_void, length).unwrap();
+    }
+
+    #[test]
+    fn anonymous_page_is_zeroed_and_writable() {
+        let page = get_page_size();
+        let block = mem_map(page, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as *mut u8;
+        let bytes = unsafe { std::slice::from_raw_parts_mut(block, page) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        bytes.fill(0xab);
+        assert!(bytes.iter().all(|&b| b == 0xab));
+        mem_unmap(block as *const c_void, page).unwrap();
+    }
+
+    #[test]
+    fn populated_mapping_is_zeroed_and_writable() {
+        let length = 16 * get_page_size();
+        let block = mem_map(length, MMAP_PROT_READ_WRITE, MMAP_POPULATE).unwrap() as *mut u8;
+        let bytes = unsafe { std::slice::from_raw_parts_mut(block, length) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        bytes.fill(0x5a);
+        mem_unmap(block as *const c_void, length).unwrap();
+    }
+
+    #[test]
+    fn discarded_pages_stay_mapped() {
+        let length = 4 * get_page_size();
+        let block = mem_map(length, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as *mut u8;
+        let bytes = unsafe { std::slice::from_raw_parts_mut(block, length) };
+        bytes.fill(0x42);
+        mem_discard(block as *const c_void, length).unwrap();
+        bytes.fill(0x24);
+        assert!(bytes.iter().all(|&b| b == 0x24));
+        mem_unmap(block as *const c_void, length).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn remapped_mapping_keeps_its_contents() {
+        use super::mem_remap;
+
+        let page = get_page_size();
+        let block = mem_map(page, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap() as *mut u8;
+        unsafe { block.write_bytes(0x3c, page) };
+        let grown = mem_remap(block as *const c_void, page, 64 * page).unwrap() as *mut u8;
+        let bytes = unsafe { std::slice::from_raw_parts_mut(grown, 64 * page) };
+        assert!(bytes[..page].iter().all(|&b| b == 0x3c));
+        assert!(bytes[page..].iter().all(|&b| b == 0));
+        mem_unmap(grown as *const c_void, 64 * page).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binding_to_a_missing_node_fails() {
+        use super::mem_bind;
+
+        let page = get_page_size();
+        let block = mem_map(page, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS).unwrap();
+        assert!(mem_bind(block, page, 64).is_err());
+        // No machine this runs on has that many nodes.
+        assert!(mem_bind(block, page, 63).is_err());
+        mem_unmap(block, page).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn page_size_is_cached() {
+        let page = get_page_size();
+        assert!(page.is_power_of_two());
+        assert_eq!(super::PAGE_SIZE.get(), Some(&page));
+        assert_eq!(get_page_size(), page);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unmap_reports_errno() {
+        // EINVAL: the address is not page aligned.
+        let err = mem_unmap(56 as *const c_void, 64).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(22));
+        #[cfg(feature = "std")]
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,184 @@
+//! Side table from live allocations to their requested sizes, for the
+//! `size-map` feature.
+//!
+//! The table is updated from inside the allocator, so it cannot live on the
+//! heap it describes: it is an open-addressing table in mappings of its own.
+
+use core::{ffi::c_void, mem, ptr};
+
+use crate::{
+    mmap::{mem_map, mem_unmap, MMAP_NO_FLAGS, MMAP_PROT_READ_WRITE},
+    sync::Mutex,
+};
+
+/// Requested sizes of every live allocation of the global entry points.
+pub(crate) static SIZES: Mutex<SizeMap> = Mutex::new(SizeMap::new());
+
+/// Slot keys with a special meaning; neither is ever a data pointer.
+const EMPTY: usize = 0;
+const REMOVED: usize = usize::MAX;
+const MIN_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    key: usize,
+    size: usize,
+}
+
+pub(crate) struct SizeMap {
+    slots: *mut Slot,
+    /// Number of slots, a power of two.
+    capacity: usize,
+    /// Slots that are not empty, removed ones included.
+    used: usize,
+}
+
+unsafe impl Send for SizeMap {}
+
+impl SizeMap {
+    pub(crate) const fn new() -> Self {
+        Self {
+            slots: ptr::null_mut(),
+            capacity: 0,
+            used: 0,
+        }
+    }
+
+    fn slot(&self, key: usize) -> usize {
+        // Data pointers are 16-byte aligned, so the low bits carry nothing.
+        (key >> 4)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .rotate_left(32)
+            & (self.capacity - 1)
+    }
+
+    /// Index of the slot holding `key`, or of the empty slot ending its
+    /// probe sequence.
+    fn find(&self, key: usize) -> usize {
+        let mut index = self.slot(key);
+        loop {
+            let slot_key = unsafe { (*self.slots.add(index)).key };
+            if slot_key == key || slot_key == EMPTY {
+                return index;
+            }
+            index = (index + 1) & (self.capacity - 1);
+        }
+    }
+
+    /// Records `size` for `ptr`. Returns false, changing nothing, when the
+    /// table is full and cannot be grown.
+    pub(crate) fn insert(&mut self, ptr: *const c_void, size: usize) -> bool {
+        // Keep at least half of the slots empty so probes stay short.
+        if (self.used + 1) * 2 > self.capacity && !self.grow() {
+            return false;
+        }
+        let index = self.find(ptr as usize);
+        let slot = unsafe { &mut *self.slots.add(index) };
+        if slot.key == EMPTY {
+            self.used += 1;
+        }
+        *slot = Slot {
+            key: ptr as usize,
+            size,
+        };
+        true
+    }
+
+    pub(crate) fn get(&self, ptr: *const c_void) -> Option<usize> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let slot = unsafe { *self.slots.add(self.find(ptr as usize)) };
+        (slot.key == ptr as usize).then_some(slot.size)
+    }
+
+    pub(crate) fn remove(&mut self, ptr: *const c_void) -> Option<usize> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let slot = unsafe { &mut *self.slots.add(self.find(ptr as usize)) };
+        if slot.key != ptr as usize {
+            return None;
+        }
+        slot.key = REMOVED;
+        Some(slot.size)
+    }
+
+    /// Drops every entry and unmaps the table.
+    pub(crate) fn clear(&mut self) {
+        if !self.slots.is_null() {
+            let _ = mem_unmap(
+                self.slots as *const c_void,
+                self.capacity * mem::size_of::<Slot>(),
+            );
+        }
+        *self = Self::new();
+    }
+
+    /// Moves every entry into a table twice the size, or the same size when
+    /// most slots only hold removed entries.
+    fn grow(&mut self) -> bool {
+        let old = (self.slots, self.capacity);
+        let live = (0..old.1)
+            .filter(|i| !matches!(unsafe { (*old.0.add(*i)).key }, EMPTY | REMOVED))
+            .count();
+        let capacity = if live * 4 < old.1 {
+            old.1
+        } else {
+            (old.1 * 2).max(MIN_CAPACITY)
+        };
+        let length = capacity * mem::size_of::<Slot>();
+        let Ok(slots) = mem_map(length, MMAP_PROT_READ_WRITE, MMAP_NO_FLAGS) else {
+            return false;
+        };
+        // Fresh anonymous pages are zeroed, so every slot starts out empty.
+        self.slots = slots as *mut Slot;
+        self.capacity = capacity;
+        self.used = 0;
+        for i in 0..old.1 {
+            let slot = unsafe { *old.0.add(i) };
+            if !matches!(slot.key, EMPTY | REMOVED) {
+                let index = self.find(slot.key);
+                unsafe { self.slots.add(index).write(slot) };
+                self.used += 1;
+            }
+        }
+        if !old.0.is_null() {
+            let _ = mem_unmap(old.0 as *const c_void, old.1 * mem::size_of::<Slot>());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod size_map_tests {
+    use std::ffi::c_void;
+
+    use super::SizeMap;
+
+    #[test]
+    fn entries_survive_growth_and_removal() {
+        let mut map = SizeMap::new();
+        let key = |i: usize| (0x1000 + i * 16) as *const c_void;
+        for i in 0..5000 {
+            assert!(map.insert(key(i), i));
+        }
+        for i in (0..5000).step_by(2) {
+            assert_eq!(map.remove(key(i)), Some(i));
+        }
+        // Churn through removed slots.
+        for round in 0..4 {
+            for i in 0..5000 {
+                map.insert(key(10_000 + i), round);
+                map.remove(key(10_000 + i));
+            }
+        }
+        for i in 0..5000 {
+            let expected = (i % 2 == 1).then_some(i);
+            assert_eq!(map.get(key(i)), expected);
+        }
+        assert_eq!(map.get(key(10_000)), None);
+        map.clear();
+        assert_eq!(map.get(key(1)), None);
+    }
+}
@@ -0,0 +1,235 @@
+//! The locks guarding heap lists.
+//!
+//! With `std` the arenas use the standard mutex. Without it, a spinlock with
+//! the same interface takes its place; it is never poisoned, so the
+//! `PoisonError` paths of its callers are dead.
+//!
+//! An [`Allocator`](crate::Allocator) picks its lock through [`HeapLock`],
+//! which also offers the spinlock with `std` and no lock at all for
+//! allocators that never leave their thread.
+
+use core::{
+    cell::{RefCell, RefMut},
+    ops::DerefMut,
+};
+
+#[cfg(feature = "std")]
+pub(crate) use std::sync::{Mutex, MutexGuard, PoisonError};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use spin::Mutex;
+#[cfg(all(not(feature = "std"), feature = "size-map"))]
+pub(crate) use spin::PoisonError;
+
+/// How an [`Allocator`](crate::Allocator) guards its heap list.
+///
+/// The implementors are markers naming a strategy; the lock itself is
+/// [`Lock`](Self::Lock).
+pub trait HeapLock {
+    /// A `T` behind this kind of lock.
+    type Lock<T>;
+    /// Access to the `T` for as long as the lock is held.
+    type Guard<'a, T: 'a>: DerefMut<Target = T>;
+
+    /// Puts `value` behind a new lock.
+    fn new<T>(value: T) -> Self::Lock<T>;
+
+    /// Waits for the lock and takes it.
+    fn lock<T>(lock: &Self::Lock<T>) -> Self::Guard<'_, T>;
+
+    /// Reaches the value through an exclusive borrow, without locking.
+    fn get_mut<T>(lock: &mut Self::Lock<T>) -> &mut T;
+}
+
+/// The standard mutex, putting waiting threads to sleep. A lock poisoned by a
+/// panic is taken over as is. Needs `std`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MutexLock;
+
+#[cfg(feature = "std")]
+impl HeapLock for MutexLock {
+    type Lock<T> = Mutex<T>;
+    type Guard<'a, T: 'a> = MutexGuard<'a, T>;
+
+    fn new<T>(value: T) -> Mutex<T> {
+        Mutex::new(value)
+    }
+
+    fn lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+        lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get_mut<T>(lock: &mut Mutex<T>) -> &mut T {
+        lock.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A spinlock, busy-waiting instead of sleeping. Cheaper than the mutex when
+/// the lock is rarely contended.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinLock;
+
+impl HeapLock for SpinLock {
+    type Lock<T> = spin::Mutex<T>;
+    type Guard<'a, T: 'a> = spin::MutexGuard<'a, T>;
+
+    fn new<T>(value: T) -> spin::Mutex<T> {
+        spin::Mutex::new(value)
+    }
+
+    fn lock<T>(lock: &spin::Mutex<T>) -> spin::MutexGuard<'_, T> {
+        lock.lock().unwrap_or_else(spin::PoisonError::into_inner)
+    }
+
+    fn get_mut<T>(lock: &mut spin::Mutex<T>) -> &mut T {
+        lock.get_mut().unwrap_or_else(spin::PoisonError::into_inner)
+    }
+}
+
+/// No lock at all. An allocator using it cannot be shared between threads,
+/// and takes no atomic operation per call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoLock;
+
+impl HeapLock for NoLock {
+    type Lock<T> = RefCell<T>;
+    type Guard<'a, T: 'a> = RefMut<'a, T>;
+
+    fn new<T>(value: T) -> RefCell<T> {
+        RefCell::new(value)
+    }
+
+    fn lock<T>(lock: &RefCell<T>) -> RefMut<'_, T> {
+        lock.borrow_mut()
+    }
+
+    fn get_mut<T>(lock: &mut RefCell<T>) -> &mut T {
+        lock.get_mut()
+    }
+}
+
+/// The lock the arenas use: [`MutexLock`] with `std`, [`SpinLock`] without.
+#[cfg(feature = "std")]
+pub type DefaultLock = MutexLock;
+
+/// The lock the arenas use: [`MutexLock`] with `std`, [`SpinLock`] without.
+#[cfg(not(feature = "std"))]
+pub type DefaultLock = SpinLock;
+
+mod spin {
+    use core::{
+        cell::UnsafeCell,
+        convert::Infallible,
+        hint,
+        marker::PhantomData,
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    pub struct Mutex<T> {
+        locked: AtomicBool,
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for Mutex<T> {}
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub(crate) const fn new(value: T) -> Self {
+            Self {
+                locked: AtomicBool::new(false),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        pub(crate) fn lock(&self) -> Result<MutexGuard<'_, T>, PoisonError<MutexGuard<'_, T>>> {
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                while self.locked.load(Ordering::Relaxed) {
+                    hint::spin_loop();
+                }
+            }
+            Ok(MutexGuard { mutex: self })
+        }
+
+        pub(crate) fn get_mut(&mut self) -> Result<&mut T, PoisonError<&mut T>> {
+            Ok(self.value.get_mut())
+        }
+    }
+
+    pub struct MutexGuard<'a, T> {
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<T> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.mutex.value.get() }
+        }
+    }
+
+    impl<T> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.mutex.value.get() }
+        }
+    }
+
+    impl<T> Drop for MutexGuard<'_, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+        }
+    }
+
+    /// Never built: a panic while holding a spinlock does not poison it.
+    pub struct PoisonError<T>(Infallible, PhantomData<T>);
+
+    impl<T> PoisonError<T> {
+        pub(crate) fn into_inner(self) -> T {
+            match self.0 {}
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+mod spin_tests {
+    use std::{sync::Arc, thread};
+
+    use super::spin::{Mutex, PoisonError};
+
+    #[test]
+    fn spinlock_serializes_increments() {
+        let counter = Arc::new(Mutex::new(0usize));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        *counter.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            *counter.lock().unwrap_or_else(PoisonError::into_inner),
+            40_000
+        );
+    }
+
+    #[test]
+    fn malloc_and_free_without_std() {
+        let ptr = crate::malloc(100) as *mut u8;
+        assert!(!ptr.is_null());
+        unsafe {
+            ptr.write_bytes(0x42, 100);
+            crate::free(ptr as *mut core::ffi::c_void);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Links the exported C symbols, which replace the process allocator, so it
+//! runs without the libtest harness.
+
+use std::{ffi::c_void, ptr};
+
+use my_alloc as _;
+
+extern "C" {
+    fn malloc(size: usize) -> *mut c_void;
+    fn calloc(count: usize, size: usize) -> *mut c_void;
+    fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+    fn reallocarray(ptr: *mut c_void, count: usize, size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+    fn posix_memalign(memptr: *mut *mut c_void, alignment: usize, size: usize) -> i32;
+}
+
+fn round_trip() {
+    unsafe {
+        let ptr = malloc(32) as *mut u8;
+        ptr.write_bytes(0x11, 32);
+        let ptr = realloc(ptr as *mut c_void, 4000) as *mut u8;
+        assert!((0..32).all(|i| ptr.add(i).read() == 0x11));
+        free(ptr as *mut c_void);
+
+        let ptr = malloc(16);
+        assert!(reallocarray(ptr, usize::MAX, 2).is_null());
+        let ptr = reallocarray(ptr, 100, 8);
+        assert!(!ptr.is_null());
+        free(ptr);
+
+        let zeroed = calloc(16, 4) as *mut u8;
+        assert!((0..64).all(|i| zeroed.add(i).read() == 0));
+        free(zeroed as *mut c_void);
+        free(ptr::null_mut());
+    }
+}
+
+fn memalign() {
+    unsafe {
+        let mut ptr = ptr::null_mut();
+        assert_eq!(posix_memalign(&mut ptr, 256, 100), 0);
+        assert_eq!(ptr as usize % 256, 0);
+        free(ptr);
+        assert_eq!(posix_memalign(&mut ptr, 24, 100), 22);
+        assert_eq!(posix_memalign(&mut ptr, 4, 100), 22);
+    }
+}
+
+/// Pointers from elsewhere, such as libc's own `memalign`, reach the exported
+/// symbols too and must not bring the process down.
+fn foreign_pointers() {
+    let mut foreign = [7u64; 4];
+    let ptr = foreign.as_mut_ptr() as *mut c_void;
+    unsafe {
+        assert!(realloc(ptr, 64).is_null());
+        assert!(realloc(ptr, 0).is_null());
+        assert!(reallocarray(ptr, 8, 8).is_null());
+        free(ptr);
+    }
+    assert_eq!(foreign, [7; 4]);
+}
+
+fn main() {
+    round_trip();
+    memalign();
+    foreign_pointers();
+}
@@ -0,0 +1,32 @@
+//! Runs without the libtest harness so that the only allocations going
+//! through the crate are the ones made here.
+
+use my_alloc::MurymiAlloc;
+
+#[global_allocator]
+static A: MurymiAlloc = MurymiAlloc;
+
+fn vec_and_box_use_the_crate_allocator() {
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in 0..10_000 {
+        bytes.push(i as u8);
+    }
+    assert!(bytes.iter().enumerate().all(|(i, b)| *b == i as u8));
+
+    let words: Box<[u64]> = (0..512u64).collect();
+    assert_eq!(words.iter().sum::<u64>(), 511 * 512 / 2);
+}
+
+fn over_aligned_box() {
+    #[repr(align(64))]
+    struct Line([u8; 64]);
+
+    let line = Box::new(Line([7; 64]));
+    assert_eq!(&*line as *const Line as usize % 64, 0);
+    assert!(line.0.iter().all(|b| *b == 7));
+}
+
+fn main() {
+    vec_and_box_use_the_crate_allocator();
+    over_aligned_box();
+}
@@ -0,0 +1,107 @@
+//! Replays a byte string as a sequence of `malloc`, `free` and `realloc`
+//! calls, shared by the `random_ops` test and the fuzz target.
+//!
+//! Every live allocation is filled with a byte of its own and checked before
+//! it is resized or freed, so writes landing in the wrong block show up as a
+//! mismatch. The heap structures are verified after every call.
+
+use std::ffi::c_void;
+
+use my_alloc::{free, malloc_usable_size, realloc, try_free, try_malloc, verify_integrity};
+
+struct Live {
+    ptr: *mut u8,
+    size: usize,
+    fill: u8,
+}
+
+/// Panics unless the first `len` bytes at `ptr` all hold `fill`.
+fn check(ptr: *const u8, len: usize, fill: u8) {
+    let bytes = unsafe { std::slice::from_raw_parts(ptr, len) };
+    if let Some(at) = bytes.iter().position(|&byte| byte != fill) {
+        panic!("{ptr:p}: byte {at} of {len} overwritten");
+    }
+}
+
+/// Decodes a size from the next two bytes. The top bit scales it up by 16,
+/// reaching past the large threshold, so every heap group gets exercised.
+fn next_size(bytes: &mut impl Iterator<Item = u8>) -> usize {
+    let raw = u16::from_le_bytes([bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)]);
+    let size = usize::from(raw & 0x7fff);
+    if raw & 0x8000 != 0 {
+        size << 4
+    } else {
+        size
+    }
+}
+
+fn pick(bytes: &mut impl Iterator<Item = u8>, live: &[Live]) -> Option<usize> {
+    let index = usize::from(bytes.next()?);
+    (!live.is_empty()).then(|| index % live.len())
+}
+
+pub fn run(data: &[u8]) {
+    let mut bytes = data.iter().copied();
+    let mut live: Vec<Live> = Vec::new();
+    let mut freed: Vec<*mut u8> = Vec::new();
+    let mut fill = 0u8;
+    while let Some(op) = bytes.next() {
+        fill = fill.wrapping_add(1);
+        match op % 4 {
+            0 => {
+                let size = next_size(&mut bytes);
+                if let Some(ptr) = try_malloc(size) {
+                    let ptr = ptr.as_ptr();
+                    assert!(malloc_usable_size(ptr as *const c_void) >= size);
+                    unsafe { ptr.write_bytes(fill, size) };
+                    live.push(Live { ptr, size, fill });
+                }
+            }
+            1 => {
+                let Some(index) = pick(&mut bytes, &live) else {
+                    continue;
+                };
+                let block = live.swap_remove(index);
+                check(block.ptr, block.size, block.fill);
+                unsafe { try_free(block.ptr as *mut c_void) }.unwrap();
+                freed.push(block.ptr);
+            }
+            2 => {
+                let Some(index) = pick(&mut bytes, &live) else {
+                    continue;
+                };
+                let size = next_size(&mut bytes);
+                let block = &mut live[index];
+                check(block.ptr, block.size, block.fill);
+                let ptr = unsafe { realloc(block.ptr as *mut c_void, size) } as *mut u8;
+                if size == 0 {
+                    freed.push(live.swap_remove(index).ptr);
+                } else if !ptr.is_null() {
+                    // The bytes both sizes have in common come along.
+                    check(ptr, block.size.min(size), block.fill);
+                    unsafe { ptr.write_bytes(fill, size) };
+                    *block = Live { ptr, size, fill };
+                }
+            }
+            _ => {
+                // Pointers freed earlier must be refused, unless the memory
+                // has been handed out again since.
+                let Some(&ptr) = freed.last() else {
+                    continue;
+                };
+                if live.iter().all(|block| block.ptr != ptr) {
+                    assert!(unsafe { try_free(ptr as *mut c_void) }.is_err());
+                }
+                freed.pop();
+            }
+        }
+        if let Err(violation) = verify_integrity() {
+            panic!("after op {op}: {violation}");
+        }
+    }
+    for block in live {
+        check(block.ptr, block.size, block.fill);
+        unsafe { free(block.ptr as *mut c_void) };
+    }
+    verify_integrity().unwrap();
+}
@@ -0,0 +1,25 @@
+//! Random sequences of `malloc`, `free` and `realloc`, checked against a
+//! shadow model of the live allocations and `verify_integrity`.
+//!
+//! The fuzz target under `fuzz/` replays the same operations from inputs
+//! chosen by libFuzzer; this runs a fixed set of pseudo-random ones on every
+//! `cargo test`.
+
+mod ops;
+
+/// xorshift64*, enough to spread the operations around.
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+#[test]
+fn random_sequences_keep_the_heap_intact() {
+    let mut state = 0x9e37_79b9_7f4a_7c15;
+    for _ in 0..50 {
+        let data: Vec<u8> = (0..512).map(|_| next(&mut state) as u8).collect();
+        ops::run(&data);
+    }
+}